@@ -0,0 +1,20 @@
+//! Decide which architectures get the tracers' live-register constructors,
+//! so the list lives in one place for the library and the examples.
+use std::env;
+
+/// 可以读取当前寄存器的架构，见`MachineState::current`
+const LIVE_CAPTURE: &[&str] = &["riscv32", "riscv64", "loongarch64", "aarch64", "x86_64"];
+
+/// 其中`Arch::DECODES_PROLOGUE`的架构，只有它们提供实时回溯的`CompilerTracer`
+const LIVE_PROLOGUE: &[&str] = &["riscv32", "riscv64", "loongarch64"];
+
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(live_capture, live_prologue)");
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+    if LIVE_CAPTURE.contains(&arch.as_str()) {
+        println!("cargo:rustc-cfg=live_capture");
+    }
+    if LIVE_PROLOGUE.contains(&arch.as_str()) {
+        println!("cargo:rustc-cfg=live_prologue");
+    }
+}
@@ -1,18 +1,24 @@
-use tracer::{FramePointTracer, Tracer, TracerProvider};
-
 fn main() {
-    let tracer = FramePointTracer::new(Provider);
-    tracer.trace().for_each(|x| {
-        println!(
-            "func_name: {}, func_addr: {:#x}, bias: {:#x}",
-            x.func_name, x.func_addr, x.bias
-        );
-    });
-}
-struct Provider;
-impl TracerProvider for Provider {
-    fn address2symbol(&self, addr: usize) -> Option<(usize, &'static str)> {
-        println!("addr: {}", addr);
-        None
+    // 只有build.rs中列出的架构可以读取当前的寄存器
+    #[cfg(live_capture)]
+    {
+        use tracer::{FramePointTracer, Tracer, TracerProvider};
+
+        struct Provider;
+
+        impl TracerProvider for Provider {
+            fn address2symbol(&self, addr: usize) -> Option<(usize, &'static str)> {
+                println!("addr: {}", addr);
+                None
+            }
+        }
+
+        let tracer = FramePointTracer::new(Provider);
+        tracer.trace().for_each(|x| {
+            println!(
+                "func_name: {}, func_addr: {:#x}, bias: {:#x}",
+                x.func_name, x.func_addr, x.bias
+            );
+        });
     }
 }
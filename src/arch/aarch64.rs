@@ -0,0 +1,77 @@
+use super::{Arch, PrologueInstruction};
+use crate::{MemoryReader, TraceError};
+use gimli::{AArch64 as Regs, Register};
+
+/// AArch64 with the AAPCS64 frame record chain.
+///
+/// `x29` points to a frame record holding the caller's `x29` and the return
+/// address `x30`. Return addresses signed with pointer authentication are
+/// stripped assuming 48-bit virtual addresses: bit 55 selects whether the
+/// upper bits are filled with ones (kernel) or zeros (user).
+///
+/// gimli 0.26 does not understand `DW_CFA_AARCH64_negate_ra_state`, so the
+/// FDE of a function built with `-Z branch-protection=pac-ret` cannot be
+/// parsed and the `DwarfTracer` stops there with `NoUnwindInfo`.
+///
+/// Prologue scanning is not implemented, the `CompilerTracer` reports
+/// `PrologueNotRecognized` for every function.
+#[derive(Debug, Copy, Clone)]
+pub struct AArch64;
+
+/// The number of bits of a virtual address.
+const VA_BITS: u32 = 48;
+
+impl Arch for AArch64 {
+    const SP: Register = Regs::SP;
+    const FP: Register = Regs::X29;
+    const RA: Register = Regs::X30;
+    const WORD_SIZE: usize = 8;
+    const FRAME_RA_OFFSET: isize = 8;
+    const FRAME_FP_OFFSET: isize = 0;
+    const FRAME_CFA_OFFSET: isize = 16;
+    const FPR_BASE: u16 = 64;
+    const ZERO: Option<Register> = None;
+    const DECODES_PROLOGUE: bool = false;
+
+    /// `sp`, `x19`-`x29` and `v8`-`v15`.
+    fn is_callee_saved(reg: Register) -> bool {
+        matches!(reg.0, 19..=29 | 31 | 72..=79)
+    }
+
+    fn strip_return_address(ra: u64) -> u64 {
+        let mask = (1 << VA_BITS) - 1;
+        if ra & (1 << 55) != 0 {
+            ra | !mask
+        } else {
+            ra & mask
+        }
+    }
+
+    fn decode_prologue<R: MemoryReader>(
+        _reader: &R,
+        _addr: usize,
+    ) -> Result<(PrologueInstruction, usize), TraceError> {
+        Ok((PrologueInstruction::Other, 4))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_pointer_authentication_code() {
+        assert_eq!(
+            AArch64::strip_return_address(0x002a_0000_4000_1234),
+            0x0000_0000_4000_1234
+        );
+        assert_eq!(
+            AArch64::strip_return_address(0xff9a_ffff_8000_1234),
+            0xffff_ffff_8000_1234
+        );
+        assert_eq!(
+            AArch64::strip_return_address(0xffff_ffff_8000_1234),
+            0xffff_ffff_8000_1234
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use super::{read_u32, Arch, PrologueInstruction};
+use crate::{MemoryReader, TraceError};
+use bit_field::BitField;
+use gimli::Register;
+
+/// LoongArch64 with the standard (LP64D) calling convention.
+///
+/// gimli 0.26 has no register names for LoongArch, the DWARF numbers are
+/// `$r0`-`$r31` as 0-31 and `$f0`-`$f31` as 32-63.
+#[derive(Debug, Copy, Clone)]
+pub struct LoongArch64;
+
+impl LoongArch64 {
+    pub const RA: Register = Register(1);
+    pub const SP: Register = Register(3);
+    /// `$fp`, also known as `$s9`
+    pub const FP: Register = Register(22);
+}
+
+// addi.d rd,rj,si12
+// 0000001011 [si12] rj rd
+const ADDI_D: u32 = 0b0000001011;
+// jirl $zero,$ra,0
+const RET: u32 = 0x4c00_0020;
+// ld.d rd,rj,si12
+// 0010100011 [si12] rj rd
+const LD_D: u32 = 0b0010100011;
+// st.d rd,rj,si12
+// 0010100111 [si12] rj rd
+const ST_D: u32 = 0b0010100111;
+
+impl Arch for LoongArch64 {
+    const SP: Register = Self::SP;
+    const FP: Register = Self::FP;
+    const RA: Register = Self::RA;
+    const WORD_SIZE: usize = 8;
+    const FRAME_RA_OFFSET: isize = -8;
+    const FRAME_FP_OFFSET: isize = -16;
+    const FRAME_CFA_OFFSET: isize = 0;
+    const FPR_BASE: u16 = 32;
+    const ZERO: Option<Register> = Some(Register(0));
+
+    /// `$sp`, `$fp`, `$s0`-`$s8` and `$fs0`-`$fs7`.
+    fn is_callee_saved(reg: Register) -> bool {
+        matches!(reg.0, 3 | 22..=31 | 56..=63)
+    }
+
+    fn decode_prologue<R: MemoryReader>(
+        reader: &R,
+        addr: usize,
+    ) -> Result<(PrologueInstruction, usize), TraceError> {
+        let ins = read_u32(reader, addr)?;
+        if ins == RET {
+            return Ok((PrologueInstruction::Return, 4));
+        }
+        let rd = ins.get_bits(0..5) as u16;
+        let rj = ins.get_bits(5..10) as u16;
+        // si12 符号扩展
+        let imm = ((ins.get_bits(10..22) << 20) as i32) >> 20;
+        let decoded = match ins.get_bits(22..32) {
+            // addi.d $sp,$sp,-imm
+            ADDI_D if rd == Self::SP.0 && rj == Self::SP.0 && imm < 0 => {
+                PrologueInstruction::StackAlloc(-imm as usize)
+            }
+            // addi.d $sp,$sp,imm
+            ADDI_D if rd == Self::SP.0 && rj == Self::SP.0 && imm > 0 => {
+                PrologueInstruction::StackFree(imm as usize)
+            }
+            // addi.d $fp,$sp,imm
+            ADDI_D if rd == Self::FP.0 && rj == Self::SP.0 && imm >= 0 => {
+                PrologueInstruction::SetFp(imm as usize)
+            }
+            // addi.d $sp,$fp,-imm
+            ADDI_D if rd == Self::SP.0 && rj == Self::FP.0 && imm <= 0 => {
+                PrologueInstruction::SpFromFp
+            }
+            // ld.d $fp,$sp,off
+            LD_D if rd == Self::FP.0 && rj == Self::SP.0 => PrologueInstruction::RestoreFp,
+            // st.d $ra,$sp,off
+            ST_D if rd == Self::RA.0 && rj == Self::SP.0 && imm >= 0 => {
+                PrologueInstruction::SaveRa(imm as usize)
+            }
+            // st.d $fp,$sp,off
+            ST_D if rd == Self::FP.0 && rj == Self::SP.0 && imm >= 0 => {
+                PrologueInstruction::SaveFp(imm as usize)
+            }
+            _ => PrologueInstruction::Other,
+        };
+        Ok((decoded, 4))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MemoryImage;
+
+    fn decode(ins: u32) -> PrologueInstruction {
+        let mut memory = MemoryImage::new();
+        memory.write_u32(0x1000, ins);
+        let (decoded, len) = LoongArch64::decode_prologue(&memory, 0x1000).unwrap();
+        assert_eq!(len, 4);
+        decoded
+    }
+
+    #[test]
+    fn decodes_prologue_instructions() {
+        // addi.d $sp,$sp,-32
+        assert_eq!(decode(0x02ff_8063), PrologueInstruction::StackAlloc(32));
+        // st.d $ra,$sp,24
+        assert_eq!(decode(0x29c0_6061), PrologueInstruction::SaveRa(24));
+        // st.d $fp,$sp,16
+        assert_eq!(decode(0x29c0_4076), PrologueInstruction::SaveFp(16));
+        // addi.d $fp,$sp,32
+        assert_eq!(decode(0x02c0_8076), PrologueInstruction::SetFp(32));
+        // st.d $s0,$sp,8
+        assert_eq!(decode(0x29c0_2077), PrologueInstruction::Other);
+        // addi.d $sp,$sp,32
+        assert_eq!(decode(0x02c0_8063), PrologueInstruction::StackFree(32));
+        // jirl $zero,$ra,0
+        assert_eq!(decode(0x4c00_0020), PrologueInstruction::Return);
+        // addi.d $sp,$fp,-32
+        assert_eq!(decode(0x02ff_82c3), PrologueInstruction::SpFromFp);
+        // ld.d $fp,$sp,16
+        assert_eq!(decode(0x28c0_4076), PrologueInstruction::RestoreFp);
+    }
+}
@@ -0,0 +1,123 @@
+//! Architecture specific conventions used by the tracers.
+//!
+//! Every tracer takes the architecture as a type parameter that defaults to
+//! [`NativeArch`], so a kernel can also unwind a saved [`MachineState`] of
+//! another architecture.
+//!
+//! [`MachineState`]: crate::MachineState
+mod aarch64;
+mod loongarch64;
+mod riscv;
+mod x86_64;
+
+pub use aarch64::AArch64;
+pub use loongarch64::LoongArch64;
+pub use riscv::{RiscV32, RiscV64};
+pub use x86_64::X86_64;
+
+use crate::{MemoryReader, TraceError};
+use core::fmt::Debug;
+use gimli::Register;
+
+/// The architecture the crate is compiled for. Other targets fall back to
+/// riscv64, which only matters when unwinding a saved `MachineState`.
+#[cfg(target_arch = "riscv32")]
+pub type NativeArch = RiscV32;
+#[cfg(target_arch = "loongarch64")]
+pub type NativeArch = LoongArch64;
+#[cfg(target_arch = "aarch64")]
+pub type NativeArch = AArch64;
+#[cfg(target_arch = "x86_64")]
+pub type NativeArch = X86_64;
+#[cfg(not(any(
+    target_arch = "riscv32",
+    target_arch = "loongarch64",
+    target_arch = "aarch64",
+    target_arch = "x86_64"
+)))]
+pub type NativeArch = RiscV64;
+
+/// An instruction the `CompilerTracer` cares about while scanning a prologue
+/// or an epilogue.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PrologueInstruction {
+    /// `sp -= size`
+    StackAlloc(usize),
+    /// The return address is stored at `sp + offset`.
+    SaveRa(usize),
+    /// The caller's frame pointer is stored at `sp + offset`.
+    SaveFp(usize),
+    /// `fp = sp + offset`, the frame is addressed through fp from now on.
+    SetFp(usize),
+    /// `sp += size` in an epilogue.
+    StackFree(usize),
+    /// `sp = fp - size` in an epilogue, undoing any dynamic change of sp.
+    SpFromFp,
+    /// The caller's frame pointer is reloaded from the stack in an epilogue.
+    RestoreFp,
+    /// Return to the address in the return address register.
+    Return,
+    Other,
+}
+
+pub trait Arch: Debug + Copy {
+    /// The DWARF number of the stack pointer.
+    const SP: Register;
+    /// The DWARF number of the frame pointer.
+    const FP: Register;
+    /// The DWARF number of the return address register.
+    const RA: Register;
+    /// The size of a register and of a stack slot in bytes, XLEN / 8 on riscv.
+    const WORD_SIZE: usize;
+    /// Where the return address is saved in a frame record, relative to fp.
+    const FRAME_RA_OFFSET: isize;
+    /// Where the caller's fp is saved in a frame record, relative to fp.
+    const FRAME_FP_OFFSET: isize;
+    /// The caller's sp relative to fp, assuming the frame record is at the
+    /// top of the frame.
+    const FRAME_CFA_OFFSET: isize;
+    /// The DWARF number of the first floating-point register.
+    const FPR_BASE: u16;
+    /// The register hardwired to zero, if any.
+    const ZERO: Option<Register>;
+    /// Whether [`Arch::decode_prologue`] recognizes stack allocations and
+    /// `ra` saves. Only then can a leaf function be told apart from a frame
+    /// that saved `ra`.
+    const DECODES_PROLOGUE: bool = true;
+
+    /// Whether the psABI requires the callee to preserve `reg`. A frame
+    /// without a rule for such a register leaves the caller's value in place.
+    fn is_callee_saved(reg: Register) -> bool;
+
+    /// Remove anything that is not part of the address from a saved return
+    /// address, e.g. a pointer authentication code.
+    fn strip_return_address(ra: u64) -> u64 {
+        ra
+    }
+
+    /// Read a register sized stack slot.
+    fn read_word<R: MemoryReader>(reader: &R, addr: usize) -> Option<u64> {
+        match Self::WORD_SIZE {
+            4 => reader.read_u32(addr).map(u64::from),
+            _ => reader.read_u64(addr),
+        }
+    }
+
+    /// Decode the instruction at `addr`, returning it together with its length.
+    fn decode_prologue<R: MemoryReader>(
+        reader: &R,
+        addr: usize,
+    ) -> Result<(PrologueInstruction, usize), TraceError>;
+}
+
+fn read_u16<R: MemoryReader>(reader: &R, addr: usize) -> Result<u16, TraceError> {
+    reader
+        .read_u16(addr)
+        .ok_or(TraceError::UnreadableMemory(addr))
+}
+
+fn read_u32<R: MemoryReader>(reader: &R, addr: usize) -> Result<u32, TraceError> {
+    reader
+        .read_u32(addr)
+        .ok_or(TraceError::UnreadableMemory(addr))
+}
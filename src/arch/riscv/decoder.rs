@@ -0,0 +1,360 @@
+//! A small RV64GC/RV32GC decoder for the prologue scanner.
+//!
+//! Only the instructions the unwinders care about are decoded, everything
+//! else is [`Instruction::Other`]. [`instruction_len`] still follows the
+//! length encoding of every instruction, so a scan never lands in the middle
+//! of one.
+use bit_field::BitField;
+
+/// A decoded instruction, registers are `x0`-`x31`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    /// `addi rd,rs1,imm`
+    Addi {
+        rd: u8,
+        rs1: u8,
+        imm: i32,
+    },
+    /// `sd rs2,imm(rs1)`, rv64 only
+    Sd {
+        rs1: u8,
+        rs2: u8,
+        imm: i32,
+    },
+    /// `sw rs2,imm(rs1)`
+    Sw {
+        rs1: u8,
+        rs2: u8,
+        imm: i32,
+    },
+    /// `ld rd,imm(rs1)`, rv64 only
+    Ld {
+        rd: u8,
+        rs1: u8,
+        imm: i32,
+    },
+    /// `lw rd,imm(rs1)`
+    Lw {
+        rd: u8,
+        rs1: u8,
+        imm: i32,
+    },
+    /// `jalr rd,imm(rs1)`
+    Jalr {
+        rd: u8,
+        rs1: u8,
+        imm: i32,
+    },
+    /// `c.addi rd,imm`
+    CAddi {
+        rd: u8,
+        imm: i32,
+    },
+    /// `c.addi16sp imm`
+    CAddi16sp {
+        imm: i32,
+    },
+    /// `c.addi4spn rd,sp,imm`
+    CAddi4spn {
+        rd: u8,
+        imm: u32,
+    },
+    /// `c.sdsp rs2,imm(sp)`, rv64 only
+    CSdsp {
+        rs2: u8,
+        imm: u32,
+    },
+    /// `c.swsp rs2,imm(sp)`
+    CSwsp {
+        rs2: u8,
+        imm: u32,
+    },
+    /// `c.ldsp rd,imm(sp)`, rv64 only
+    CLdsp {
+        rd: u8,
+        imm: u32,
+    },
+    /// `c.lwsp rd,imm(sp)`
+    CLwsp {
+        rd: u8,
+        imm: u32,
+    },
+    /// `c.jr rs1`
+    CJr {
+        rs1: u8,
+    },
+    Other,
+}
+
+/// The length in bytes of the instruction starting with the parcel `low`.
+pub fn instruction_len(low: u16) -> usize {
+    if low.get_bits(0..2) != 0b11 {
+        2
+    } else if low.get_bits(2..5) != 0b111 {
+        4
+    } else if low.get_bits(5..6) == 0 {
+        6
+    } else if low.get_bits(6..7) == 0 {
+        8
+    } else if low.get_bits(12..15) != 0b111 {
+        // (80 + 16 * nnn) 位
+        10 + 2 * low.get_bits(12..15) as usize
+    } else {
+        // 保留的编码，按最短的长度跳过
+        2
+    }
+}
+
+/// Decode a 2 or 4 byte instruction, `word` is XLEN in bytes. Compressed
+/// instructions only use the low 16 bits of `ins`.
+pub fn decode(ins: u32, word: usize) -> Instruction {
+    if instruction_len(ins as u16) == 2 {
+        decode_compressed(ins as u16, word)
+    } else {
+        decode_normal(ins, word)
+    }
+}
+
+fn decode_normal(ins: u32, word: usize) -> Instruction {
+    let rd = ins.get_bits(7..12) as u8;
+    let funct3 = ins.get_bits(12..15);
+    let rs1 = ins.get_bits(15..20) as u8;
+    let rs2 = ins.get_bits(20..25) as u8;
+    // I型立即数 imm[11:0]
+    let i_imm = (ins as i32) >> 20;
+    // S型立即数 imm[11:5] imm[4:0]
+    let s_imm = ((ins as i32) >> 25 << 5) | ins.get_bits(7..12) as i32;
+    match (ins.get_bits(0..7), funct3) {
+        (0b0010011, 0b000) => Instruction::Addi {
+            rd,
+            rs1,
+            imm: i_imm,
+        },
+        (0b0000011, 0b011) if word == 8 => Instruction::Ld {
+            rd,
+            rs1,
+            imm: i_imm,
+        },
+        (0b0000011, 0b010) => Instruction::Lw {
+            rd,
+            rs1,
+            imm: i_imm,
+        },
+        (0b0100011, 0b011) if word == 8 => Instruction::Sd {
+            rs1,
+            rs2,
+            imm: s_imm,
+        },
+        (0b0100011, 0b010) => Instruction::Sw {
+            rs1,
+            rs2,
+            imm: s_imm,
+        },
+        (0b1100111, 0b000) => Instruction::Jalr {
+            rd,
+            rs1,
+            imm: i_imm,
+        },
+        _ => Instruction::Other,
+    }
+}
+
+fn decode_compressed(ins: u16, word: usize) -> Instruction {
+    let rd = ins.get_bits(7..12) as u8;
+    let rs2 = ins.get_bits(2..7) as u8;
+    match (ins.get_bits(13..16), ins.get_bits(0..2)) {
+        // c.addi4spn
+        // 000 [nzuimm5:4|9:6|2|3] rd' 00
+        (0b000, 0b00) => {
+            let mut imm = 0u32;
+            imm.set_bits(4..6, ins.get_bits(11..13) as u32);
+            imm.set_bits(6..10, ins.get_bits(7..11) as u32);
+            imm.set_bit(2, ins.get_bit(6));
+            imm.set_bit(3, ins.get_bit(5));
+            // 全零是非法指令
+            if imm == 0 {
+                return Instruction::Other;
+            }
+            Instruction::CAddi4spn {
+                rd: 8 + ins.get_bits(2..5) as u8,
+                imm,
+            }
+        }
+        // c.addi
+        // 000 [imm5] rd [imm4:0] 01
+        (0b000, 0b01) => {
+            let mut imm = 0u32;
+            imm.set_bits(0..5, ins.get_bits(2..7) as u32);
+            imm.set_bit(5, ins.get_bit(12));
+            Instruction::CAddi {
+                rd,
+                imm: sign_extend(imm, 6),
+            }
+        }
+        // c.addi16sp, rd不是sp时是c.lui
+        // 011 [imm9] 00010 [imm4|6|8|7|5] 01
+        (0b011, 0b01) if rd == 2 => {
+            let mut imm = 0u32;
+            imm.set_bit(9, ins.get_bit(12));
+            imm.set_bit(8, ins.get_bit(4));
+            imm.set_bit(7, ins.get_bit(3));
+            imm.set_bit(6, ins.get_bit(5));
+            imm.set_bit(5, ins.get_bit(2));
+            imm.set_bit(4, ins.get_bit(6));
+            Instruction::CAddi16sp {
+                imm: sign_extend(imm, 10),
+            }
+        }
+        // c.lwsp, rd为0时是保留的编码
+        // 010 [uimm5] rd [uimm4:2|7:6] 10
+        (0b010, 0b10) if rd != 0 => {
+            let mut imm = 0u32;
+            imm.set_bit(5, ins.get_bit(12));
+            imm.set_bits(2..5, ins.get_bits(4..7) as u32);
+            imm.set_bits(6..8, ins.get_bits(2..4) as u32);
+            Instruction::CLwsp { rd, imm }
+        }
+        // c.ldsp, rv32上是c.flwsp
+        // 011 [uimm5] rd [uimm4:3|8:6] 10
+        (0b011, 0b10) if word == 8 && rd != 0 => {
+            let mut imm = 0u32;
+            imm.set_bit(5, ins.get_bit(12));
+            imm.set_bits(3..5, ins.get_bits(5..7) as u32);
+            imm.set_bits(6..9, ins.get_bits(2..5) as u32);
+            Instruction::CLdsp { rd, imm }
+        }
+        // c.jr
+        // 100 0 rs1 00000 10
+        (0b100, 0b10) if !ins.get_bit(12) && rs2 == 0 && rd != 0 => Instruction::CJr { rs1: rd },
+        // c.swsp
+        // 110 [uimm5:2|7:6] rs2 10
+        (0b110, 0b10) => {
+            let mut imm = 0u32;
+            imm.set_bits(2..6, ins.get_bits(9..13) as u32);
+            imm.set_bits(6..8, ins.get_bits(7..9) as u32);
+            Instruction::CSwsp { rs2, imm }
+        }
+        // c.sdsp, rv32上是c.fswsp
+        // 111 [uimm5:3|8:6] rs2 10
+        (0b111, 0b10) if word == 8 => {
+            let mut imm = 0u32;
+            imm.set_bits(3..6, ins.get_bits(10..13) as u32);
+            imm.set_bits(6..9, ins.get_bits(7..10) as u32);
+            Instruction::CSdsp { rs2, imm }
+        }
+        _ => Instruction::Other,
+    }
+}
+
+/// 将低`bits`位符号扩展
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    let shift = 32 - bits;
+    ((value << shift) as i32) >> shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_encoding() {
+        // c.addi sp,-16
+        assert_eq!(instruction_len(0x1141), 2);
+        // addi sp,sp,-32
+        assert_eq!(instruction_len(0x0113), 4);
+        assert_eq!(instruction_len(0x001f), 6);
+        assert_eq!(instruction_len(0x003f), 8);
+        assert_eq!(instruction_len(0x107f), 12);
+    }
+
+    #[test]
+    fn decodes_base_instructions() {
+        assert_eq!(
+            decode(0xfe01_0113, 8),
+            Instruction::Addi {
+                rd: 2,
+                rs1: 2,
+                imm: -32
+            }
+        );
+        assert_eq!(
+            decode(0x0011_3c23, 8),
+            Instruction::Sd {
+                rs1: 2,
+                rs2: 1,
+                imm: 24
+            }
+        );
+        // sd ra,-8(s0)
+        assert_eq!(
+            decode(0xfe14_3c23, 8),
+            Instruction::Sd {
+                rs1: 8,
+                rs2: 1,
+                imm: -8
+            }
+        );
+        assert_eq!(decode(0x0011_3c23, 4), Instruction::Other);
+        assert_eq!(
+            decode(0x0011_2623, 4),
+            Instruction::Sw {
+                rs1: 2,
+                rs2: 1,
+                imm: 12
+            }
+        );
+        assert_eq!(
+            decode(0x0000_8067, 8),
+            Instruction::Jalr {
+                rd: 0,
+                rs1: 1,
+                imm: 0
+            }
+        );
+        assert_eq!(
+            decode(0x0181_3083, 8),
+            Instruction::Ld {
+                rd: 1,
+                rs1: 2,
+                imm: 24
+            }
+        );
+        assert_eq!(decode(0x0181_3083, 4), Instruction::Other);
+        // lw s0,8(sp)
+        assert_eq!(
+            decode(0x0081_2403, 4),
+            Instruction::Lw {
+                rd: 8,
+                rs1: 2,
+                imm: 8
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_compressed_instructions() {
+        assert_eq!(decode(0x1141, 8), Instruction::CAddi { rd: 2, imm: -16 });
+        assert_eq!(decode(0x7179, 8), Instruction::CAddi16sp { imm: -48 });
+        assert_eq!(decode(0x1800, 8), Instruction::CAddi4spn { rd: 8, imm: 48 });
+        assert_eq!(decode(0xf406, 8), Instruction::CSdsp { rs2: 1, imm: 40 });
+        assert_eq!(decode(0xf406, 4), Instruction::Other);
+        assert_eq!(decode(0xc606, 4), Instruction::CSwsp { rs2: 1, imm: 12 });
+        assert_eq!(decode(0x8082, 8), Instruction::CJr { rs1: 1 });
+        assert_eq!(decode(0x7402, 8), Instruction::CLdsp { rd: 8, imm: 32 });
+        assert_eq!(decode(0x7402, 4), Instruction::Other);
+        assert_eq!(decode(0x4432, 4), Instruction::CLwsp { rd: 8, imm: 12 });
+        // c.lui a0,1
+        assert_eq!(decode(0x6505, 8), Instruction::Other);
+        // c.mv a0,a1
+        assert_eq!(decode(0x852e, 8), Instruction::Other);
+        assert_eq!(decode(0x0000, 8), Instruction::Other);
+    }
+
+    #[test]
+    fn upper_half_is_not_decoded() {
+        // lui t0,0xe4060 的高16位是c.sdsp ra,8(sp)的编码
+        let ins = 0xe406_02b7u32;
+        assert_eq!(instruction_len(ins as u16), 4);
+        assert_eq!(decode(ins, 8), Instruction::Other);
+    }
+}
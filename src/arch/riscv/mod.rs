@@ -0,0 +1,217 @@
+mod decoder;
+
+use super::{read_u16, read_u32, Arch, PrologueInstruction};
+use crate::{MemoryReader, TraceError};
+use decoder::{decode, instruction_len, Instruction};
+use gimli::{Register, RiscV};
+use log::trace;
+
+/// riscv64 with the standard calling convention.
+#[derive(Debug, Copy, Clone)]
+pub struct RiscV64;
+
+/// riscv32 with the standard calling convention.
+#[derive(Debug, Copy, Clone)]
+pub struct RiscV32;
+
+impl Arch for RiscV64 {
+    const SP: Register = RiscV::SP;
+    const FP: Register = RiscV::S0;
+    const RA: Register = RiscV::RA;
+    const WORD_SIZE: usize = 8;
+    const FRAME_RA_OFFSET: isize = -8;
+    const FRAME_FP_OFFSET: isize = -16;
+    const FRAME_CFA_OFFSET: isize = 0;
+    const FPR_BASE: u16 = 32;
+    const ZERO: Option<Register> = Some(RiscV::ZERO);
+
+    fn is_callee_saved(reg: Register) -> bool {
+        is_callee_saved(reg)
+    }
+
+    fn decode_prologue<R: MemoryReader>(
+        reader: &R,
+        addr: usize,
+    ) -> Result<(PrologueInstruction, usize), TraceError> {
+        decode_prologue(reader, addr, Self::WORD_SIZE)
+    }
+}
+
+impl Arch for RiscV32 {
+    const SP: Register = RiscV::SP;
+    const FP: Register = RiscV::S0;
+    const RA: Register = RiscV::RA;
+    const WORD_SIZE: usize = 4;
+    const FRAME_RA_OFFSET: isize = -4;
+    const FRAME_FP_OFFSET: isize = -8;
+    const FRAME_CFA_OFFSET: isize = 0;
+    const FPR_BASE: u16 = 32;
+    const ZERO: Option<Register> = Some(RiscV::ZERO);
+
+    fn is_callee_saved(reg: Register) -> bool {
+        is_callee_saved(reg)
+    }
+
+    fn decode_prologue<R: MemoryReader>(
+        reader: &R,
+        addr: usize,
+    ) -> Result<(PrologueInstruction, usize), TraceError> {
+        decode_prologue(reader, addr, Self::WORD_SIZE)
+    }
+}
+
+/// `sp`, `s0`-`s11` and `fs0`-`fs11`.
+fn is_callee_saved(reg: Register) -> bool {
+    matches!(reg.0, 2 | 8 | 9 | 18..=27 | 40 | 41 | 50..=59)
+}
+
+/// `word` is XLEN in bytes, it decides whether `ra` is saved with `sd` or `sw`.
+fn decode_prologue<R: MemoryReader>(
+    reader: &R,
+    addr: usize,
+    word: usize,
+) -> Result<(PrologueInstruction, usize), TraceError> {
+    let low = read_u16(reader, addr)?;
+    let len = instruction_len(low);
+    let ins = match len {
+        2 => decode(low as u32, word),
+        4 => decode(read_u32(reader, addr)?, word),
+        _ => Instruction::Other,
+    };
+    trace!("{:#x}: {:?}", addr, ins);
+    let ins = match ins {
+        // 开辟或者释放栈空间
+        Instruction::Addi {
+            rd: SP,
+            rs1: SP,
+            imm,
+        }
+        | Instruction::CAddi { rd: SP, imm }
+        | Instruction::CAddi16sp { imm } => sp_adjust(imm),
+        // 设置帧指针
+        Instruction::Addi {
+            rd: S0,
+            rs1: SP,
+            imm,
+        } if imm >= 0 => PrologueInstruction::SetFp(imm as usize),
+        Instruction::CAddi4spn { rd: S0, imm } => PrologueInstruction::SetFp(imm as usize),
+        // 尾声中通过帧指针恢复sp
+        Instruction::Addi {
+            rd: SP,
+            rs1: S0,
+            imm,
+        } if imm <= 0 => PrologueInstruction::SpFromFp,
+        // 以sp为基址保存寄存器
+        Instruction::Sd { rs1: SP, rs2, imm } if word == 8 && imm >= 0 => store(rs2, imm as u32),
+        Instruction::Sw { rs1: SP, rs2, imm } if word == 4 && imm >= 0 => store(rs2, imm as u32),
+        Instruction::CSdsp { rs2, imm } => store(rs2, imm),
+        Instruction::CSwsp { rs2, imm } if word == 4 => store(rs2, imm),
+        // 尾声中从栈上恢复调用者的s0
+        Instruction::Ld {
+            rd: S0, rs1: SP, ..
+        } if word == 8 => PrologueInstruction::RestoreFp,
+        Instruction::Lw {
+            rd: S0, rs1: SP, ..
+        } if word == 4 => PrologueInstruction::RestoreFp,
+        Instruction::CLdsp { rd: S0, .. } => PrologueInstruction::RestoreFp,
+        Instruction::CLwsp { rd: S0, .. } if word == 4 => PrologueInstruction::RestoreFp,
+        // ret
+        Instruction::Jalr {
+            rd: 0,
+            rs1: RA,
+            imm: 0,
+        }
+        | Instruction::CJr { rs1: RA } => PrologueInstruction::Return,
+        _ => PrologueInstruction::Other,
+    };
+    Ok((ins, len))
+}
+
+const RA: u8 = 1;
+const SP: u8 = 2;
+const S0: u8 = 8;
+
+/// `imm`是加到`sp`上的值，负数开辟栈空间，正数在尾声中释放栈空间
+fn sp_adjust(imm: i32) -> PrologueInstruction {
+    match imm {
+        imm if imm < 0 => PrologueInstruction::StackAlloc(-imm as usize),
+        imm if imm > 0 => PrologueInstruction::StackFree(imm as usize),
+        _ => PrologueInstruction::Other,
+    }
+}
+
+/// 保存`ra`或者`s0`到`sp + offset`
+fn store(reg: u8, offset: u32) -> PrologueInstruction {
+    match reg {
+        RA => PrologueInstruction::SaveRa(offset as usize),
+        S0 => PrologueInstruction::SaveFp(offset as usize),
+        _ => PrologueInstruction::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MemoryImage;
+
+    fn decode<A: Arch>(ins: &[u8]) -> PrologueInstruction {
+        let mut memory = MemoryImage::new();
+        memory.write_bytes(0x1000, ins);
+        A::decode_prologue(&memory, 0x1000).unwrap().0
+    }
+
+    #[test]
+    fn ra_store_matches_xlen() {
+        // sd ra,24(sp)
+        let sd = 0x0011_3c23u32.to_le_bytes();
+        // sw ra,12(sp)
+        let sw = 0x0011_2623u32.to_le_bytes();
+        // c.sdsp ra,8(sp)
+        let c_sdsp = 0xe406u16.to_le_bytes();
+        // c.swsp ra,12(sp)
+        let c_swsp = 0xc606u16.to_le_bytes();
+        assert_eq!(decode::<RiscV64>(&sd), PrologueInstruction::SaveRa(24));
+        assert_eq!(decode::<RiscV64>(&c_sdsp), PrologueInstruction::SaveRa(8));
+        assert_eq!(decode::<RiscV64>(&sw), PrologueInstruction::Other);
+        assert_eq!(decode::<RiscV64>(&c_swsp), PrologueInstruction::Other);
+        assert_eq!(decode::<RiscV32>(&sw), PrologueInstruction::SaveRa(12));
+        assert_eq!(decode::<RiscV32>(&c_swsp), PrologueInstruction::SaveRa(12));
+        assert_eq!(decode::<RiscV32>(&sd), PrologueInstruction::Other);
+        assert_eq!(decode::<RiscV32>(&c_sdsp), PrologueInstruction::Other);
+        // c.sdsp s1,8(sp) and c.swsp s1,12(sp) store another register
+        assert_eq!(
+            decode::<RiscV64>(&0xe426u16.to_le_bytes()),
+            PrologueInstruction::Other
+        );
+        assert_eq!(
+            decode::<RiscV32>(&0xc626u16.to_le_bytes()),
+            PrologueInstruction::Other
+        );
+    }
+
+    #[test]
+    fn decodes_epilogue_instructions() {
+        // ld s0,16(sp)
+        let ld = 0x0101_3403u32.to_le_bytes();
+        // lw s0,8(sp)
+        let lw = 0x0081_2403u32.to_le_bytes();
+        // c.ldsp s0,32(sp)
+        let c_ldsp = 0x7402u16.to_le_bytes();
+        // c.lwsp s0,12(sp)
+        let c_lwsp = 0x4432u16.to_le_bytes();
+        assert_eq!(decode::<RiscV64>(&ld), PrologueInstruction::RestoreFp);
+        assert_eq!(decode::<RiscV64>(&c_ldsp), PrologueInstruction::RestoreFp);
+        assert_eq!(decode::<RiscV32>(&lw), PrologueInstruction::RestoreFp);
+        assert_eq!(decode::<RiscV32>(&c_lwsp), PrologueInstruction::RestoreFp);
+        // ld ra,24(sp)
+        assert_eq!(
+            decode::<RiscV64>(&0x0181_3083u32.to_le_bytes()),
+            PrologueInstruction::Other
+        );
+        // addi sp,s0,-32
+        assert_eq!(
+            decode::<RiscV64>(&0xfe04_0113u32.to_le_bytes()),
+            PrologueInstruction::SpFromFp
+        );
+    }
+}
@@ -0,0 +1,38 @@
+use super::{Arch, PrologueInstruction};
+use crate::{MemoryReader, TraceError};
+use gimli::{Register, X86_64 as Regs};
+
+/// x86_64 with the System V ABI.
+///
+/// `rbp` points to the saved `rbp` of the caller, followed by the return
+/// address pushed by `call`. The DWARF return address column is `rip`.
+///
+/// Prologue scanning is not implemented, the `CompilerTracer` reports
+/// `PrologueNotRecognized` for every function.
+#[derive(Debug, Copy, Clone)]
+pub struct X86_64;
+
+impl Arch for X86_64 {
+    const SP: Register = Regs::RSP;
+    const FP: Register = Regs::RBP;
+    const RA: Register = Regs::RA;
+    const WORD_SIZE: usize = 8;
+    const FRAME_RA_OFFSET: isize = 8;
+    const FRAME_FP_OFFSET: isize = 0;
+    const FRAME_CFA_OFFSET: isize = 16;
+    const FPR_BASE: u16 = 17;
+    const ZERO: Option<Register> = None;
+    const DECODES_PROLOGUE: bool = false;
+
+    /// `rbx`, `rbp`, `rsp` and `r12`-`r15`.
+    fn is_callee_saved(reg: Register) -> bool {
+        matches!(reg.0, 3 | 6 | 7 | 12..=15)
+    }
+
+    fn decode_prologue<R: MemoryReader>(
+        _reader: &R,
+        _addr: usize,
+    ) -> Result<(PrologueInstruction, usize), TraceError> {
+        Ok((PrologueInstruction::Other, 1))
+    }
+}
@@ -1,319 +1,752 @@
-use crate::utils::{read_instruction, read_instruction_short, read_value};
-use crate::{TraceInfo, Tracer, TracerProvider};
-use bit_field::BitField;
-use core::arch::asm;
-use log::{info, trace};
-
-// 在函数第一条指令，开辟栈空间
-// 指令 addi sp,sp,imm 4字节
-// imm[11:0] rs 000 rd 0010011
-// 有三种二字节的压缩指令
-// c.addi rd,imm
-// 000 [imm5] rd [imm4-0] 01
-// c.addi16sp imm
-// 011 [imm9] 00010 [imm4|6|8|7|5] 01
-#[derive(Debug, Copy, Clone)]
-enum InstructionSp {
-    Addi(u32),
-    CAddi(u32),
-    CAddi16Sp(u32),
+use crate::arch::{Arch, NativeArch};
+use crate::machine::MachineState;
+use crate::memory::{MemoryReader, VolatileReader};
+use crate::prologue::Prologue;
+use crate::walk::{Cursor, CursorWalk, Unwind, Walk};
+use crate::{Frame, TraceError, Tracer, TracerProvider, UnwindMethod};
+use core::marker::PhantomData;
+use core::ops::Range;
+use log::info;
+
+pub struct CompilerTracer<T, R = VolatileReader, A = NativeArch> {
+    provider: T,
+    reader: R,
+    /// The register state to start from, `None` means the live registers.
+    state: Option<MachineState>,
+    /// The stack the walk may not leave, see [`Self::with_stack`].
+    stack: Option<Range<usize>>,
+    arch: PhantomData<A>,
 }
-impl InstructionSp {
-    fn try_new(ins: u32, f: fn(i32) -> bool) -> Option<Self> {
-        let opcode = ins.get_bits(0..7);
-        match opcode {
-            0b0010011 => {
-                // 高12位符号扩展
-                let mut imm = ins.get_bits(20..32);
-                for i in 12..32 {
-                    imm.set_bit(i, imm.get_bit(11));
-                }
-                let imm = imm as i32;
-                if f(imm) {
-                    Some(InstructionSp::Addi((-imm) as u32))
-                } else {
-                    None
-                }
-            }
-            _ => {
-                let short_ins = ins.get_bits(0..16);
-                let high = short_ins.get_bits(13..16);
-                let low = short_ins.get_bits(0..2);
-                match (high, low) {
-                    (0b000, 0b01) => {
-                        // 保证是sp
-                        let rd = short_ins.get_bits(7..12);
-                        if rd != 2 {
-                            return None;
-                        }
-                        let mut imm = 0;
-                        imm.set_bits(0..5, short_ins.get_bits(2..7));
-                        imm.set_bit(5, short_ins.get_bit(12));
-                        // 符号扩展
-                        for i in 6..32 {
-                            imm.set_bit(i, imm.get_bit(5));
-                        }
-                        let imm = imm as i32;
-                        trace!("[CADDI] {:#b}", imm);
-                        if f(imm) {
-                            Some(InstructionSp::CAddi((-imm) as u32))
-                        } else {
-                            None
-                        }
-                    }
-                    (0b011, 0b01) => {
-                        let flag = short_ins.get_bits(7..=11);
-                        if flag != 0b00010 {
-                            return None;
-                        }
-                        let mut imm = 0u32;
-                        imm.set_bit(9, short_ins.get_bit(12));
-                        imm.set_bit(8, short_ins.get_bit(4));
-                        imm.set_bit(7, short_ins.get_bit(3));
-                        imm.set_bit(6, short_ins.get_bit(5));
-                        imm.set_bit(5, short_ins.get_bit(2));
-                        imm.set_bit(4, short_ins.get_bit(6));
-                        for i in 10..32 {
-                            imm.set_bit(i, imm.get_bit(9));
-                        }
-                        let imm = imm as i32;
-                        trace!("sp_size: {}", -imm);
-                        if f(imm) {
-                            Some(InstructionSp::CAddi16Sp((-imm) as u32))
-                        } else {
-                            None
-                        }
-                    }
-                    _ => None,
-                }
-            }
-        }
-    }
+
+/// Capture the registers of the caller of this function. The frame of
+/// `capture_caller` is gone by the time the walk reaches it, so it is
+/// unwound here while it is still live, from its own prologue.
+#[inline(never)]
+fn capture_caller<R: MemoryReader>(reader: &R) -> Result<MachineState, TraceError> {
+    let state = MachineState::current();
+    caller_state::<NativeArch, _>(reader, capture_caller::<R> as *const () as usize, state)
 }
 
-fn check_sd_ra(ins: u32) -> Option<u32> {
-    // 检查指令是否是存储ra
-    let opcode = ins.get_bits(0..7);
-    return match opcode {
-        0b0100011 => {
-            // 四字节的sd指令
-            let func = ins.get_bits(12..=14);
-            if func != 0b011 {
-                return None;
-            }
-            let rd = ins.get_bits(15..=19); // sp
-            let rt = ins.get_bits(20..=24); // ra
-            if rd != 2 || rt != 1 {
-                return None;
-            }
-            let mut imm = 0u32;
-            imm.set_bits(0..=4, ins.get_bits(7..=11));
-            imm.set_bits(5..=11, ins.get_bits(25..=31));
-            for i in 12..32 {
-                imm.set_bit(i, imm.get_bit(11));
-            }
-            let imm = imm as isize;
-            if imm > 0 {
-                Some(imm as u32)
-            } else {
-                None
-            }
-        }
-        _ => {
-            // 2字节的sd指令
-            // c.sdsp
-            // 111 [uimm5:3 8:6] rt 10
-            let short_ins = ins.get_bits(0..16);
-            let high = short_ins.get_bits(13..16);
-            let low = short_ins.get_bits(0..2);
-            match (high, low) {
-                (0b111, 0b10) => {
-                    let mut imm = 0u32;
-                    imm.set_bits(3..6, short_ins.get_bits(10..13));
-                    imm.set_bits(6..9, short_ins.get_bits(7..10));
-                    Some(imm)
-                }
-                (_, _) => None,
-            }
+/// The state of the caller of the function starting at `start`, given the
+/// registers captured inside it before it called anything. `ra` is then
+/// still the return address, only fp may have to be read from the stack.
+fn caller_state<A: Arch, R: MemoryReader>(
+    reader: &R,
+    start: usize,
+    state: MachineState,
+) -> Result<MachineState, TraceError> {
+    let (sp, fp) = (state.sp as usize, state.fp as usize);
+    let prologue = Prologue::scan::<A, _>(reader, start, state.pc as usize)?;
+    let cfa = prologue.cfa::<A>(sp, fp)?;
+    let caller_fp = match prologue.fp_save {
+        Some(fp_save) => {
+            let addr = Prologue::slot(cfa, fp_save)?;
+            A::read_word(reader, addr).ok_or(TraceError::UnreadableMemory(addr))?
         }
+        None => state.fp,
     };
+    // 调用者已经调用了capture_caller，ra保存在它自己的栈帧中
+    Ok(MachineState::new(state.ra, cfa as u64, caller_fp, 0))
 }
 
-pub struct CompilerTracer<T> {
-    provider: T,
+#[cfg(live_prologue)]
+impl<T> CompilerTracer<T> {
+    pub fn new(provider: T) -> Self {
+        Self::with_reader(provider, VolatileReader)
+    }
 }
 
-pub struct CompilerTracerIterator<'a, T> {
-    /// The first instruction address of the function
-    f_ins_addr: usize,
-    /// The sp value
-    sp: usize,
-    /// The ra value
-    ra: usize,
-    provider: &'a T,
+#[cfg(live_prologue)]
+impl<T, R> CompilerTracer<T, R> {
+    pub fn with_reader(provider: T, reader: R) -> Self {
+        Self {
+            provider,
+            reader,
+            state: None,
+            stack: None,
+            arch: PhantomData,
+        }
+    }
 }
+
 impl<T> CompilerTracer<T> {
-    pub fn new(provider: T) -> Self {
-        Self { provider }
+    /// Unwind from `state` instead of the live registers.
+    pub fn from_state(provider: T, state: MachineState) -> Self {
+        Self::from_state_with_reader(provider, state, VolatileReader)
+    }
+}
+
+impl<T, R, A: Arch> CompilerTracer<T, R, A> {
+    /// Unwind from `state`, which may belong to an architecture other than
+    /// [`NativeArch`].
+    pub fn from_state_with_reader(provider: T, state: MachineState, reader: R) -> Self {
+        Self {
+            provider,
+            reader,
+            state: Some(state),
+            stack: None,
+            arch: PhantomData,
+        }
+    }
+
+    /// Never leave `stack`, see [`TracerProvider::stack_range`].
+    pub fn with_stack(mut self, stack: Range<usize>) -> Self {
+        self.stack = Some(stack);
+        self
+    }
+}
+
+impl<T: TracerProvider, R: MemoryReader, A: Arch> Tracer for CompilerTracer<T, R, A> {
+    type Provider = T;
+
+    fn provider(&self) -> &T {
+        &self.provider
+    }
+
+    fn try_frames(&self) -> impl Iterator<Item = Result<Frame, TraceError>> + '_ {
+        CursorWalk::<_, _, A, CompilerUnwind>::new(
+            &self.provider,
+            &self.reader,
+            self.state.as_ref(),
+            self.stack.as_ref(),
+        )
     }
 }
 
-impl<T: TracerProvider> Tracer for CompilerTracer<T> {
-    fn trace(&self) -> impl Iterator<Item = TraceInfo> + '_ {
-        CompilerTracerIterator {
-            f_ins_addr: 0,
-            sp: 0,
-            ra: 0,
-            provider: &self.provider,
+/// Finds the frame of every function by scanning its prologue.
+struct CompilerUnwind;
+
+impl Unwind for CompilerUnwind {
+    const METHOD: UnwindMethod = UnwindMethod::Compiler;
+
+    // 第一次调用，从next中调用capture_caller的位置开始回溯
+    fn capture<R: MemoryReader>(reader: &R) -> Result<MachineState, TraceError> {
+        capture_caller(reader)
+    }
+
+    /// Scan the prologue of the function containing `cursor.pc` and return
+    /// the saved return address together with the caller's sp and fp.
+    fn unwind<T: TracerProvider, R: MemoryReader, A: Arch>(
+        walk: &CursorWalk<'_, T, R, A, Self>,
+        cursor: Cursor,
+    ) -> Result<Option<(usize, usize, usize)>, TraceError> {
+        let Cursor { pc, sp, fp, ra } = cursor;
+        if sp == 0 || !sp.is_multiple_of(A::WORD_SIZE) {
+            return Err(TraceError::InvalidStackPointer(sp));
+        }
+        // pc在尾声中并且栈空间已经释放，ra、sp和s0都已经恢复为调用者的值
+        if let Some(ra) = ra {
+            if Prologue::frame_released::<A, _>(walk.reader(), pc) {
+                info!("pc {:#x} is past the epilogue, ra: {:#x}", pc, ra);
+                walk.guard().check_step(walk.depth(), (pc, sp), (ra, sp))?;
+                return Ok(Some((ra, sp, fp)));
+            }
+        }
+        let f_ins_addr = walk
+            .provider()
+            .address2symbol(pc)
+            .ok_or(TraceError::SymbolNotFound(pc))?
+            .0;
+        let mut prologue = Prologue::scan::<A, _>(walk.reader(), f_ins_addr, pc)?;
+        // pc在尾声中并且s0已经恢复为调用者的值，只能通过sp找到栈帧
+        if prologue.fp_set.is_some() && Prologue::fp_restored::<A, _>(walk.reader(), pc) {
+            prologue.fp_set = None;
+        }
+        info!("f_ins_addr: {:#x}, prologue: {:?}", f_ins_addr, prologue);
+        let cfa = prologue.cfa::<A>(sp, fp)?;
+        walk.guard().check_cfa::<A>(sp, cfa)?;
+        let ra = match (prologue.ra_save, ra) {
+            // ra保存在开辟的栈空间中
+            (Some(ra_save), _) if ra_save.0 > 0 => walk.read_slot(Prologue::slot(cfa, ra_save)?)?,
+            // 叶子函数不保存ra，或者pc还没有执行到保存ra的指令，
+            // 此时ra寄存器中就是返回地址
+            (_, Some(ra)) => ra,
+            _ => return Err(TraceError::PrologueNotRecognized(f_ins_addr)),
+        };
+        info!("cfa: {:#x}, ra: {:#x}", cfa, ra);
+        if ra == 0 {
+            return Ok(None);
         }
+        walk.guard().check_step(walk.depth(), (pc, sp), (ra, cfa))?;
+        // s0是被调用者保存的寄存器，没有保存说明调用者的s0不变
+        let caller_fp = match prologue.fp_save {
+            Some(fp_save) => walk.read_slot(Prologue::slot(cfa, fp_save)?)?,
+            None => fp,
+        };
+        // back to father stack
+        Ok(Some((ra, cfa, caller_fp)))
     }
 }
 
-impl<T: TracerProvider> Iterator for CompilerTracerIterator<'_, T> {
-    type Item = TraceInfo;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.sp == 0 {
-            // 第一次调用
-            let trace_addr = Self::next as usize;
-            self.sp = {
-                let t: usize;
-                unsafe {
-                    asm!("mv {},sp",out(reg)t);
-                }
-                t
-            };
-            self.f_ins_addr = trace_addr;
-            self.ra = trace_addr;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::{LoongArch64, RiscV32, RiscV64};
+    use crate::testing::{trap_layout, Bounded, Interrupted, MemoryImage, Symbols, WithTrap};
+    use crate::StackTransition;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    const NOP: u32 = 0x0000_0013;
+    const C_NOP: u16 = 0x0001;
+
+    fn tracer(
+        memory: MemoryImage,
+        pc: u64,
+        sp: u64,
+    ) -> CompilerTracer<Symbols, MemoryImage, RiscV64> {
+        CompilerTracer::from_state_with_reader(
+            Symbols(vec![
+                (0x1000, 0x100, "foo"),
+                (0x2000, 0x100, "bar"),
+                (0x3000, 0x100, "baz"),
+            ]),
+            MachineState::new(pc, sp, 0, 0),
+            memory,
+        )
+    }
+
+    /// foo: addi sp,sp,-32; sd ra,24(sp)
+    /// bar: c.addi16sp sp,-48; c.sdsp ra,40(sp)
+    /// baz: c.addi sp,-16; c.sdsp ra,8(sp)
+    fn code() -> MemoryImage {
+        let mut memory = MemoryImage::new();
+        memory.write_u32(0x1000, 0xfe01_0113);
+        memory.write_u32(0x1004, 0x0011_3c23);
+        for addr in (0x1008..0x1100).step_by(4) {
+            memory.write_u32(addr, NOP);
+        }
+        memory.write_u16(0x2000, 0x7179);
+        memory.write_u16(0x2002, 0xf406);
+        for addr in (0x2004..0x2100).step_by(2) {
+            memory.write_u16(addr, C_NOP);
         }
-        let first_ins = read_instruction(self.f_ins_addr);
-        info!(
-            "f_ins_addr: {:#x}, short_ins:{:#x}",
-            self.f_ins_addr,
-            read_instruction_short(self.f_ins_addr)
+        memory.write_u16(0x3000, 0x1141);
+        memory.write_u16(0x3002, 0xe406);
+        for addr in (0x3004..0x3100).step_by(2) {
+            memory.write_u16(addr, C_NOP);
+        }
+        memory
+    }
+
+    #[test]
+    fn walks_scanned_prologues() {
+        let mut memory = code();
+        memory.write_u64(0x8000_0000 + 32 - 8, 0x2008);
+        memory.write_u64(0x8000_0020 + 48 - 8, 0x3006);
+        memory.write_u64(0x8000_0050 + 16 - 8, 0x9000);
+
+        let tracer = tracer(memory, 0x1010, 0x8000_0000);
+        let frames: Vec<_> = tracer
+            .trace()
+            .map(|info| (info.func_name, info.func_addr, info.bias))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                ("foo", 0x1000, 0x10),
+                ("bar", 0x2000, 0x8),
+                ("baz", 0x3000, 0x6),
+                ("??", 0x9000, 0)
+            ]
         );
-        let ans = InstructionSp::try_new(first_ins, |imm| imm < 0)?;
-        let (next_ins_addr, next_ins, mut stack_size) = match ans {
-            InstructionSp::Addi(size) => {
-                // 四字节指令
-                (
-                    self.f_ins_addr + 4,
-                    read_instruction(self.f_ins_addr + 4),
-                    size,
-                )
-            }
-            InstructionSp::CAddi(size) | InstructionSp::CAddi16Sp(size) => {
-                // 双字节指令
-                (
-                    self.f_ins_addr + 2,
-                    read_instruction(self.f_ins_addr + 2),
-                    size,
-                )
-            }
+        let frames: Vec<_> = tracer.frames().collect();
+        assert_eq!(frames[0].cfa, Some(0x8000_0020));
+        assert_eq!(frames[1].sp, 0x8000_0020);
+        assert_eq!(frames[2].ra, Some(0x9000));
+        // the start of 0x9000 is unknown, so its caller cannot be found
+        assert!(matches!(
+            tracer.try_trace().last(),
+            Some(Err(TraceError::SymbolNotFound(0x9000)))
+        ));
+    }
+
+    #[test]
+    fn enforces_stack_range_and_depth() {
+        let mut memory = code();
+        memory.write_u64(0x8000_0000 + 32 - 8, 0x2008);
+        memory.write_u64(0x8000_0020 + 48 - 8, 0x3006);
+        memory.write_u64(0x8000_0050 + 16 - 8, 0x9000);
+        let tracer = |end, max_frames| {
+            CompilerTracer::<_, _, RiscV64>::from_state_with_reader(
+                Bounded {
+                    inner: Symbols(vec![
+                        (0x1000, 0x100, "foo"),
+                        (0x2000, 0x100, "bar"),
+                        (0x3000, 0x100, "baz"),
+                    ]),
+                    stack: 0x8000_0000..end,
+                    max_frames,
+                },
+                MachineState::new(0x1010, 0x8000_0000, 0, 0),
+                memory.clone(),
+            )
         };
-        info!(
-            "next_ins:{:#x}, stack_size: {}, ra:{:#x}",
-            next_ins, stack_size, self.ra
+
+        // bar的栈帧超出了栈的范围
+        let items: Vec<_> = tracer(0x8000_0040, 16).try_frames().collect();
+        assert!(matches!(
+            items[..],
+            [Ok(_), Ok(_), Err(TraceError::OutsideStack(0x8000_0050))]
+        ));
+        let items: Vec<_> = tracer(0x8000_1000, 2).try_frames().collect();
+        assert!(matches!(
+            items[..],
+            [Ok(_), Ok(_), Err(TraceError::TooManyFrames(2))]
+        ));
+    }
+
+    #[test]
+    fn continues_from_trampoline() {
+        let mut memory = code();
+        memory.write_u64(0x8000_0000 + 32 - 8, 0x2008);
+        // 被打断的baz
+        memory.write_u64(0x9000_0000 + 16 - 8, 0);
+
+        let tracer = CompilerTracer::<_, _, RiscV64>::from_state_with_reader(
+            Interrupted {
+                inner: Symbols(vec![
+                    (0x1000, 0x100, "foo"),
+                    (0x2000, 0x100, "bar"),
+                    (0x3000, 0x100, "baz"),
+                ]),
+                trampoline: 0x2000..0x2100,
+                reason: StackTransition::Trampoline,
+                state: MachineState::new(0x3006, 0x9000_0000, 0, 0x1234),
+            },
+            MachineState::new(0x1010, 0x8000_0000, 0, 0),
+            memory,
         );
-        // 第二条指令一般会保存ra的值
-        // 需要确保第二条指令是否是存储ra
-        check_sd_ra(next_ins)?;
-        // 在一些函数中，可能不止在第一条指令中调用了addi sp,sp,imm
-        // 因此我们需要扫描函数开始到ra之间的指令，检查是否还出现了addi sp,sp,imm
-        let mut start = next_ins_addr;
-        let end = self.ra;
-        while start < end {
-            let short_ins = read_instruction_short(start);
-            if is_caddi16sp(short_ins) || is_caddi(short_ins) {
-                let ins = InstructionSp::try_new(short_ins as u32, |imm| imm < 0);
-                if ins.is_none() {
-                    start += 2;
-                    continue;
-                }
-                let ins = ins?;
-                info!("addr: {:#x}, scan short_ins: {:?}", start, ins);
-                match ins {
-                    InstructionSp::Addi(size) => {
-                        stack_size += size;
-                    }
-                    InstructionSp::CAddi(size) | InstructionSp::CAddi16Sp(size) => {
-                        stack_size += size;
-                    }
-                }
-                start += 2;
-            } else if maybe_is_addi(short_ins) {
-                let ins = read_instruction(start);
-                let ins = InstructionSp::try_new(ins, |imm| imm < 0);
-                if ins.is_none() {
-                    start += 4;
-                    continue;
-                }
-                let ins = ins?;
-                info!("addr: {:#x}, scan ins: {:?}", start, ins);
-                match ins {
-                    InstructionSp::Addi(x) => {
-                        stack_size += x;
-                    }
-                    _ => {}
-                }
-                start += 4;
-            } else {
-                start += 2;
-            }
+        let frames: Vec<_> = tracer
+            .trace()
+            .map(|info| (info.func_name, info.sp, info.cfa))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                ("foo", 0x8000_0000, Some(0x8000_0020)),
+                ("bar", 0x8000_0020, Some(0x9000_0000)),
+                ("baz", 0x9000_0000, None)
+            ]
+        );
+        assert!(tracer.try_trace().all(|item| item.is_ok()));
+    }
+
+    #[test]
+    fn unwinds_through_trap_frame() {
+        let mut memory = code();
+        memory.write_u64(0x8000_0000 + 32 - 8, 0x740);
+        // __alltraps保存的sepc、ra、sp和s0
+        memory.write_u64(0x8000_0020, 0x3006);
+        memory.write_u64(0x8000_0028, 0x1234);
+        memory.write_u64(0x8000_0030, 0x8000_0100);
+        memory.write_u64(0x8000_0038, 0);
+        memory.write_u64(0x8000_0100 + 16 - 8, 0);
+
+        let tracer = CompilerTracer::<_, _, RiscV64>::from_state_with_reader(
+            WithTrap {
+                inner: Symbols(vec![(0x1000, 0x100, "foo"), (0x3000, 0x100, "baz")]),
+                layout: trap_layout(),
+            },
+            MachineState::new(0x1010, 0x8000_0000, 0, 0),
+            memory,
+        );
+        let frames: Vec<_> = tracer
+            .trace()
+            .map(|info| (info.func_name, info.sp, info.ra))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                ("foo", 0x8000_0000, Some(0x740)),
+                ("<exception>", 0x8000_0020, Some(0x3006)),
+                ("baz", 0x8000_0100, None)
+            ]
+        );
+        assert!(tracer.try_trace().all(|item| item.is_ok()));
+    }
+
+    #[test]
+    fn stops_without_stack_allocation() {
+        let mut memory = code();
+        // ret
+        memory.write_u32(0x1000, 0x0000_8067);
+
+        let tracer = tracer(memory, 0x1010, 0x8000_0000);
+        assert_eq!(tracer.trace().count(), 1);
+        assert!(matches!(
+            tracer.try_trace().nth(1),
+            Some(Err(TraceError::PrologueNotRecognized(0x1000)))
+        ));
+    }
+
+    #[test]
+    fn stops_without_ra_save() {
+        let mut memory = code();
+        memory.write_u32(0x1004, NOP);
+
+        let tracer = tracer(memory, 0x1010, 0x8000_0000);
+        assert!(matches!(
+            tracer.try_trace().nth(1),
+            Some(Err(TraceError::PrologueNotRecognized(0x1000)))
+        ));
+    }
+
+    #[test]
+    fn stops_at_unreadable_stack() {
+        let tracer = tracer(code(), 0x1010, 0x8000_0000);
+        let items: Vec<_> = tracer.try_trace().collect();
+        assert!(matches!(
+            items[..],
+            [Ok(_), Err(TraceError::UnreadableMemory(0x8000_0018))]
+        ));
+    }
+
+    #[test]
+    fn reports_wrapping_stack_pointers() {
+        let tracer = tracer(code(), 0x1010, 0xffff_ffff_ffff_fff0);
+        assert!(matches!(
+            tracer.try_trace().nth(1),
+            Some(Err(TraceError::InvalidStackPointer(0xffff_ffff_ffff_fff0)))
+        ));
+
+        // foo: addi sp,sp,-32; sd ra,24(sp); sd s0,16(sp); addi s0,sp,32
+        let mut memory = code();
+        memory.write_u32(0x1008, 0x0081_3823);
+        memory.write_u32(0x100c, 0x0201_0413);
+        let tracer = CompilerTracer::<_, _, RiscV64>::from_state_with_reader(
+            Symbols(vec![(0x1000, 0x100, "foo")]),
+            MachineState::new(0x1010, 0x8000_0000, 0xffff_ffff_ffff_fff8, 0),
+            memory,
+        );
+        assert!(matches!(
+            tracer.try_trace().nth(1),
+            Some(Err(TraceError::InvalidStackPointer(0xffff_ffff_ffff_fff8)))
+        ));
+    }
+
+    #[test]
+    fn reports_unknown_start_symbol() {
+        let tracer = tracer(code(), 0x9000, 0x8000_0000);
+        assert!(matches!(
+            tracer.try_trace().nth(1),
+            Some(Err(TraceError::SymbolNotFound(0x9000)))
+        ));
+    }
+
+    #[test]
+    fn walks_loongarch_prologues() {
+        const NOP: u32 = 0x0340_0000;
+        let mut memory = MemoryImage::new();
+        // foo: addi.d $sp,$sp,-32; st.d $ra,$sp,24
+        memory.write_u32(0x1000, 0x02ff_8063);
+        memory.write_u32(0x1004, 0x29c0_6061);
+        // bar: addi.d $sp,$sp,-48; st.d $ra,$sp,40
+        memory.write_u32(0x2000, 0x02ff_4063);
+        memory.write_u32(0x2004, 0x29c0_a061);
+        for addr in (0x1008..0x1100).chain(0x2008..0x2100).step_by(4) {
+            memory.write_u32(addr, NOP);
         }
-        let ra_addr = self.sp + stack_size as usize - 8;
-        let ra = read_value(ra_addr); // 8字节存储
-        info!(
-            "after scan, stack size :{} ra_addr:{:#x}, ra: {:#x}",
-            stack_size, ra_addr, ra
+        memory.write_u64(0x8000_0000 + 32 - 8, 0x2010);
+        memory.write_u64(0x8000_0020 + 48 - 8, 0);
+
+        let tracer = CompilerTracer::<_, _, LoongArch64>::from_state_with_reader(
+            Symbols(vec![(0x1000, 0x100, "foo"), (0x2000, 0x100, "bar")]),
+            MachineState::new(0x1010, 0x8000_0000, 0, 0),
+            memory,
+        );
+        let frames: Vec<_> = tracer
+            .trace()
+            .map(|info| (info.func_name, info.sp, info.ra))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                ("foo", 0x8000_0000, Some(0x2010)),
+                ("bar", 0x8000_0020, None)
+            ]
         );
-        let father_func_info = self.provider.address2symbol(ra)?;
-        self.f_ins_addr = father_func_info.0;
-        self.sp += stack_size as usize; // back to father stack
-        self.ra = ra;
-        Some(TraceInfo {
-            func_name: father_func_info.1,
-            func_addr: father_func_info.0,
-            bias: ra - father_func_info.0,
-        })
+        assert!(tracer.try_trace().all(|item| item.is_ok()));
     }
-}
 
-fn is_caddi(ins: u16) -> bool {
-    let high = ins.get_bits(13..16);
-    let low = ins.get_bits(0..2);
-    match (high, low) {
-        (0b000, 0b01) => {
-            // 保证是sp
-            let rd = ins.get_bits(7..12);
-            if rd != 2 {
-                return false;
-            }
-            true
+    #[test]
+    fn walks_riscv32_prologues() {
+        let mut memory = MemoryImage::new();
+        // foo: addi sp,sp,-16; sw ra,12(sp)
+        memory.write_u32(0x1000, 0xff01_0113);
+        memory.write_u32(0x1004, 0x0011_2623);
+        for addr in (0x1008..0x1020).step_by(4) {
+            memory.write_u32(addr, NOP);
         }
-        _ => false,
+        // bar: c.addi sp,-16; c.swsp ra,12(sp)
+        memory.write_u16(0x2000, 0x1141);
+        memory.write_u16(0x2002, 0xc606);
+        for addr in (0x2004..0x2010).step_by(2) {
+            memory.write_u16(addr, C_NOP);
+        }
+        memory.write_u32(0x8000_0000 + 16 - 4, 0x2006);
+        memory.write_u32(0x8000_0010 + 16 - 4, 0);
+
+        let tracer = CompilerTracer::<_, _, RiscV32>::from_state_with_reader(
+            Symbols(vec![(0x1000, 0x100, "foo"), (0x2000, 0x100, "bar")]),
+            MachineState::new(0x1010, 0x8000_0000, 0, 0),
+            memory,
+        );
+        let frames: Vec<_> = tracer
+            .trace()
+            .map(|info| (info.func_name, info.sp, info.ra))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                ("foo", 0x8000_0000, Some(0x2006)),
+                ("bar", 0x8000_0010, None)
+            ]
+        );
+        assert!(tracer.try_trace().all(|item| item.is_ok()));
     }
-}
 
-fn is_caddi16sp(ins: u16) -> bool {
-    let high = ins.get_bits(13..16);
-    let low = ins.get_bits(0..2);
-    match (high, low) {
-        (0b011, 0b01) => {
-            let flag = ins.get_bits(7..=11);
-            if flag != 0b00010 {
-                return false;
-            }
-            true
+    #[test]
+    fn finds_ra_save_after_other_stores() {
+        let mut memory = code();
+        // foo: addi sp,sp,-32; sd s0,16(sp); c.sdsp s1,8(sp); sd ra,0(sp)
+        memory.write_u32(0x1004, 0x0081_3823);
+        memory.write_u16(0x1008, 0xe426);
+        memory.write_u16(0x100a, 0x0001);
+        memory.write_u32(0x100c, 0x0011_3023);
+        memory.write_u64(0x8000_0000, 0x2008);
+        memory.write_u64(0x8000_0010, 0xdead);
+
+        let tracer = tracer(memory, 0x1010, 0x8000_0000);
+        let frames: Vec<_> = tracer.frames().map(|frame| (frame.pc, frame.sp)).collect();
+        assert_eq!(frames[..2], [(0x1010, 0x8000_0000), (0x2008, 0x8000_0020)]);
+    }
+
+    #[test]
+    fn stops_when_only_other_registers_are_saved() {
+        let mut memory = code();
+        // baz: c.addi sp,-16; c.sdsp s0,8(sp)
+        memory.write_u16(0x3002, 0xe422);
+
+        let tracer = tracer(memory, 0x3006, 0x8000_0000);
+        assert!(matches!(
+            tracer.try_trace().nth(1),
+            Some(Err(TraceError::PrologueNotRecognized(0x3000)))
+        ));
+    }
+
+    #[test]
+    fn walks_frame_pointer_based_functions() {
+        let mut memory = code();
+        // foo: addi sp,sp,-32; sd ra,24(sp); sd s0,16(sp); addi s0,sp,32
+        //      sub sp,sp,a0; andi sp,sp,-64
+        for (i, ins) in [
+            0xfe01_0113,
+            0x0011_3c23,
+            0x0081_3823,
+            0x0201_0413,
+            0x40a1_0133,
+            0xfc01_7113,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            memory.write_u32(0x1000 + i * 4, ins);
         }
-        _ => false,
+        // bar: c.addi16sp sp,-48; c.sdsp ra,40(sp); c.sdsp s0,32(sp); c.addi4spn s0,sp,48
+        for (i, ins) in [0x7179, 0xf406, 0xf022, 0x1800].into_iter().enumerate() {
+            memory.write_u16(0x2000 + i * 2, ins);
+        }
+        memory.write_u64(0x8000_0100 - 32 + 24, 0x2010);
+        memory.write_u64(0x8000_0100 - 32 + 16, 0x8000_0200);
+        memory.write_u64(0x8000_0200 - 48 + 40, 0x9000);
+        memory.write_u64(0x8000_0200 - 48 + 32, 0);
+
+        let tracer = CompilerTracer::<_, _, RiscV64>::from_state_with_reader(
+            Symbols(vec![(0x1000, 0x100, "foo"), (0x2000, 0x100, "bar")]),
+            MachineState::new(0x1020, 0x8000_0040, 0x8000_0100, 0),
+            memory,
+        );
+        let frames: Vec<_> = tracer
+            .frames()
+            .map(|frame| (frame.pc, frame.sp, frame.cfa))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                (0x1020, 0x8000_0040, Some(0x8000_0100)),
+                (0x2010, 0x8000_0100, Some(0x8000_0200)),
+                (0x9000, 0x8000_0200, None)
+            ]
+        );
     }
-}
 
-/// imm\[11:0] rs 000 rd 0010011
-///
-/// 0001\[0 000 00010 0010011]
-/// 001--0x0113
-fn maybe_is_addi(ins: u16) -> bool {
-    ins == 0x113
+    #[test]
+    fn innermost_frame_uses_live_ra() {
+        let mut memory = code();
+        memory.write_u64(0x8000_0000 + 32 - 8, 0x2008);
+        memory.write_u64(0x8000_0020 + 48 - 8, 0);
+        // leaf: no stack allocation, no ra save
+        for addr in (0x4000..0x4010).step_by(2) {
+            memory.write_u16(addr, C_NOP);
+        }
+        let symbols = || {
+            Symbols(vec![
+                (0x1000, 0x100, "foo"),
+                (0x2000, 0x100, "bar"),
+                (0x4000, 0x100, "leaf"),
+            ])
+        };
+
+        let tracer = CompilerTracer::<_, _, RiscV64>::from_state_with_reader(
+            symbols(),
+            MachineState::new(0x4006, 0x8000_0000, 0, 0x1010),
+            memory.clone(),
+        );
+        let frames: Vec<_> = tracer
+            .trace()
+            .map(|info| (info.func_name, info.sp, info.cfa))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                ("leaf", 0x8000_0000, Some(0x8000_0000)),
+                ("foo", 0x8000_0000, Some(0x8000_0020)),
+                ("bar", 0x8000_0020, None)
+            ]
+        );
+
+        // foo trapped after addi sp,sp,-32 but before sd ra,24(sp)
+        let tracer = CompilerTracer::<_, _, RiscV64>::from_state_with_reader(
+            symbols(),
+            MachineState::new(0x1004, 0x8000_0000, 0, 0x2008),
+            memory,
+        );
+        let frames: Vec<_> = tracer
+            .trace()
+            .map(|info| (info.func_name, info.sp, info.cfa))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                ("foo", 0x8000_0000, Some(0x8000_0020)),
+                ("bar", 0x8000_0020, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn innermost_frame_in_epilogue() {
+        let mut memory = code();
+        // foo: ...; ld ra,24(sp); addi sp,sp,32; ret
+        memory.write_u32(0x1010, 0x0181_3083);
+        memory.write_u32(0x1014, 0x0201_0113);
+        memory.write_u32(0x1018, 0x0000_8067);
+        memory.write_u64(0x8000_0000 + 32 - 8, 0x2008);
+        memory.write_u64(0x8000_0020 + 48 - 8, 0);
+
+        // ra has been reloaded, the frame still exists
+        let state = MachineState::new(0x1014, 0x8000_0000, 0, 0x2008);
+        let tracer = CompilerTracer::<_, _, RiscV64>::from_state_with_reader(
+            Symbols(vec![(0x1000, 0x100, "foo"), (0x2000, 0x100, "bar")]),
+            state,
+            memory.clone(),
+        );
+        let frames: Vec<_> = tracer.frames().map(|frame| (frame.pc, frame.sp)).collect();
+        assert_eq!(frames, [(0x1014, 0x8000_0000), (0x2008, 0x8000_0020)]);
+
+        // the frame has been freed, only ret is left
+        let state = MachineState::new(0x1018, 0x8000_0020, 0, 0x2008);
+        let tracer = CompilerTracer::<_, _, RiscV64>::from_state_with_reader(
+            Symbols(vec![(0x1000, 0x100, "foo"), (0x2000, 0x100, "bar")]),
+            state,
+            memory,
+        );
+        let frames: Vec<_> = tracer.frames().map(|frame| (frame.pc, frame.sp)).collect();
+        assert_eq!(frames, [(0x1018, 0x8000_0020), (0x2008, 0x8000_0020)]);
+    }
+
+    #[test]
+    fn live_walk_starts_at_the_caller_of_the_capture() {
+        let mut memory = MemoryImage::new();
+        // capture_caller: addi sp,sp,-32; sd ra,24(sp); sd s0,16(sp); addi s0,sp,32
+        for (i, ins) in [0xfe01_0113, 0x0011_3c23, 0x0081_3823, 0x0201_0413]
+            .into_iter()
+            .enumerate()
+        {
+            memory.write_u32(0x1000 + i * 4, ins);
+        }
+        memory.write_u32(0x1010, NOP);
+        memory.write_u64(0x8000_0000 + 16, 0x8000_0100);
+        let state = MachineState::new(0x1010, 0x8000_0000, 0x8000_0020, 0x2008);
+        assert_eq!(
+            caller_state::<RiscV64, _>(&memory, 0x1000, state).unwrap(),
+            MachineState::new(0x2008, 0x8000_0020, 0x8000_0100, 0)
+        );
+
+        // a leaf capture_caller leaves every register to its caller
+        let mut memory = MemoryImage::new();
+        memory.write_u32(0x1000, NOP);
+        let state = MachineState::new(0x1004, 0x8000_0000, 0x8000_0100, 0x2008);
+        assert_eq!(
+            caller_state::<RiscV64, _>(&memory, 0x1000, state).unwrap(),
+            MachineState::new(0x2008, 0x8000_0000, 0x8000_0100, 0)
+        );
+    }
+
+    #[test]
+    fn innermost_frame_in_frame_pointer_epilogue() {
+        let mut memory = code();
+        // foo: addi sp,sp,-32; sd ra,24(sp); sd s0,16(sp); addi s0,sp,32
+        //      addi sp,s0,-32; ld ra,24(sp); ld s0,16(sp); addi sp,sp,32; ret
+        for (i, ins) in [
+            0xfe01_0113,
+            0x0011_3c23,
+            0x0081_3823,
+            0x0201_0413,
+            0xfe04_0113,
+            0x0181_3083,
+            0x0101_3403,
+            0x0201_0113,
+            0x0000_8067,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            memory.write_u32(0x1000 + i * 4, ins);
+        }
+        memory.write_u64(0x8000_0000 + 24, 0x2008);
+        memory.write_u64(0x8000_0000 + 16, 0x8000_0200);
+        memory.write_u64(0x8000_0020 + 48 - 8, 0);
+        let frames = |pc, fp| {
+            let tracer = CompilerTracer::<_, _, RiscV64>::from_state_with_reader(
+                Symbols(vec![(0x1000, 0x100, "foo"), (0x2000, 0x100, "bar")]),
+                MachineState::new(pc, 0x8000_0000, fp, 0x2008),
+                memory.clone(),
+            );
+            tracer
+                .frames()
+                .map(|frame| (frame.pc, frame.sp, frame.cfa))
+                .collect::<Vec<_>>()
+        };
+
+        // s0 is still the frame pointer of foo
+        for pc in [0x1010, 0x1014, 0x1018] {
+            assert_eq!(
+                frames(pc, 0x8000_0020),
+                [
+                    (pc as usize, 0x8000_0000, Some(0x8000_0020)),
+                    (0x2008, 0x8000_0020, None)
+                ]
+            );
+        }
+        // ld s0 has reloaded the frame pointer of bar
+        assert_eq!(
+            frames(0x101c, 0x8000_0200),
+            [
+                (0x101c, 0x8000_0000, Some(0x8000_0020)),
+                (0x2008, 0x8000_0020, None)
+            ]
+        );
+    }
 }
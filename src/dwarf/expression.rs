@@ -1 +1,183 @@
+use super::registers::RegisterSet;
+use super::unwinder::UnwinderError;
+use crate::arch::Arch;
+use crate::memory::MemoryReader;
+use crate::stack::StackGuard;
+use crate::TraceError;
+use gimli::{
+    Encoding, EvaluationResult, Expression, Format, Location, Reader, ReaderOffset, Value,
+};
+use log::trace;
 
+/// CFI expressions carry no encoding of their own, they use the target's.
+fn encoding<A: Arch>() -> Encoding {
+    Encoding {
+        address_size: A::WORD_SIZE as u8,
+        format: Format::Dwarf32,
+        version: 4,
+    }
+}
+
+/// Upper bound of executed operations, so a looping `DW_OP_skip` cannot
+/// hang the panic handler.
+const MAX_ITERATIONS: u32 = 1024;
+
+/// Evaluate a DWARF expression from a CFA or register rule.
+///
+/// `cfa` is the already computed CFA: for register rules it is pushed as the
+/// initial stack value and answers `DW_OP_call_frame_cfa`, for the CFA rule
+/// itself it is `None`. Returns the address (or value, after
+/// `DW_OP_stack_value`) the expression describes. Memory is only read where
+/// `guard` allows it.
+pub fn evaluate<R: Reader, M: MemoryReader, A: Arch>(
+    expr: Expression<R>,
+    regs: &RegisterSet<A>,
+    reader: &M,
+    guard: &StackGuard,
+    cfa: Option<u64>,
+) -> Result<u64, TraceError> {
+    let mut eval = expr.evaluation(encoding::<A>());
+    eval.set_max_iterations(MAX_ITERATIONS);
+    if let Some(cfa) = cfa {
+        eval.set_initial_value(cfa);
+    }
+    let mut result = eval.evaluate().map_err(UnwinderError::Expression)?;
+    loop {
+        trace!("expression: {:?}", result);
+        result = match result {
+            EvaluationResult::Complete => break,
+            EvaluationResult::RequiresMemory {
+                address,
+                size,
+                space: None,
+                base_type,
+            } if base_type.0 == R::Offset::from_u8(0) => {
+                let addr = address as usize;
+                guard.check_slot(addr)?;
+                let value = match size {
+                    1 => reader.read_u8(addr).map(u64::from),
+                    2 => reader.read_u16(addr).map(u64::from),
+                    4 => reader.read_u32(addr).map(u64::from),
+                    8 => reader.read_u64(addr),
+                    _ => return Err(UnwinderError::UnsupportedExpression.into()),
+                }
+                .ok_or(UnwinderError::UnreadableMemory(address))?;
+                eval.resume_with_memory(Value::Generic(value))
+            }
+            EvaluationResult::RequiresRegister {
+                register,
+                base_type,
+            } if base_type.0 == R::Offset::from_u8(0) => {
+                let value = regs
+                    .get(register)
+                    .ok_or(UnwinderError::UnknownRegister(register))?;
+                eval.resume_with_register(Value::Generic(value))
+            }
+            EvaluationResult::RequiresCallFrameCfa => {
+                let cfa = cfa.ok_or(UnwinderError::UnsupportedExpression)?;
+                eval.resume_with_call_frame_cfa(cfa)
+            }
+            _ => return Err(UnwinderError::UnsupportedExpression.into()),
+        }
+        .map_err(UnwinderError::Expression)?;
+    }
+    let value = match eval.as_result() {
+        [piece] => match piece.location {
+            Location::Address { address } => Ok(address),
+            Location::Value { value } => value.to_u64(!0).map_err(UnwinderError::Expression),
+            _ => Err(UnwinderError::UnsupportedExpression),
+        },
+        _ => Err(UnwinderError::UnsupportedExpression),
+    }?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::RiscV64;
+    use crate::machine::MachineState;
+    use crate::testing::{Bounded, MemoryImage, Symbols};
+    use alloc::vec;
+    use gimli::{EndianSlice, LittleEndian};
+
+    fn eval_within(
+        bytes: &[u8],
+        memory: &MemoryImage,
+        guard: &StackGuard,
+        cfa: Option<u64>,
+    ) -> Result<u64, TraceError> {
+        let regs =
+            RegisterSet::<RiscV64>::from_machine_state(&MachineState::new(0, 0x1000, 0x2000, 0));
+        let expr = Expression(EndianSlice::new(bytes, LittleEndian));
+        evaluate(expr, &regs, memory, guard, cfa)
+    }
+
+    fn eval(bytes: &[u8], memory: &MemoryImage, cfa: Option<u64>) -> Result<u64, TraceError> {
+        eval_within(bytes, memory, &StackGuard::new(&Symbols(vec![]), 0), cfa)
+    }
+
+    #[test]
+    fn register_relative_address() {
+        // DW_OP_breg2 16
+        let memory = MemoryImage::new();
+        assert_eq!(eval(&[0x72, 0x10], &memory, None).unwrap(), 0x1010);
+    }
+
+    #[test]
+    fn call_frame_cfa_and_initial_value() {
+        // DW_OP_call_frame_cfa; DW_OP_lit8; DW_OP_minus
+        let memory = MemoryImage::new();
+        assert_eq!(
+            eval(&[0x9c, 0x38, 0x1c], &memory, Some(0x3000)).unwrap(),
+            0x2ff8
+        );
+        // DW_OP_lit16; DW_OP_plus on top of the pushed cfa
+        assert_eq!(eval(&[0x40, 0x22], &memory, Some(0x3000)).unwrap(), 0x3010);
+        assert!(matches!(
+            eval(&[0x9c], &memory, None),
+            Err(TraceError::Unwinder(UnwinderError::UnsupportedExpression))
+        ));
+    }
+
+    #[test]
+    fn dereference_and_stack_value() {
+        // DW_OP_breg8 0; DW_OP_deref; DW_OP_stack_value
+        let mut memory = MemoryImage::new();
+        memory.write_u64(0x2000, 0x1234);
+        assert_eq!(
+            eval(&[0x78, 0x00, 0x06, 0x9f], &memory, None).unwrap(),
+            0x1234
+        );
+        assert!(matches!(
+            eval(&[0x72, 0x00, 0x06], &memory, None),
+            Err(TraceError::Unwinder(UnwinderError::UnreadableMemory(
+                0x1000
+            )))
+        ));
+
+        // 栈外的内存即使可读也不会被访问
+        let guard = StackGuard::new(
+            &Bounded {
+                inner: Symbols(vec![]),
+                stack: 0x1000..0x1800,
+                max_frames: 16,
+            },
+            0x1000,
+        );
+        assert!(matches!(
+            eval_within(&[0x78, 0x00, 0x06, 0x9f], &memory, &guard, None),
+            Err(TraceError::OutsideStack(0x2000))
+        ));
+    }
+
+    #[test]
+    fn endless_loop_is_bounded() {
+        // DW_OP_skip -3
+        let memory = MemoryImage::new();
+        assert!(matches!(
+            eval(&[0x2f, 0xfd, 0xff], &memory, None),
+            Err(TraceError::Unwinder(UnwinderError::Expression(_)))
+        ));
+    }
+}
@@ -1,8 +1,9 @@
-mod arch;
 mod expression;
+mod registers;
 mod unwinder;
 
-pub use unwinder::DwarfTracer;
+pub use registers::RegisterSet;
+pub use unwinder::{DwarfFrame, DwarfTracer, UnwinderError};
 
 /// The user should define these symbols in their linker script.
 pub trait DwarfProvider {
@@ -0,0 +1,124 @@
+use super::unwinder::UnwinderError;
+use crate::arch::{Arch, NativeArch};
+use crate::machine::MachineState;
+use core::marker::PhantomData;
+use gimli::Register;
+
+/// DWARF numbers the integer registers from 0 and the floating-point
+/// registers from [`Arch::FPR_BASE`].
+const GPR_COUNT: u16 = 32;
+const FPR_COUNT: u16 = 32;
+
+/// The end of the integer registers, x86_64 has fewer than 32 of them.
+fn gpr_end<A: Arch>() -> u16 {
+    A::FPR_BASE.min(GPR_COUNT)
+}
+
+/// The registers of one frame, indexed by their DWARF register number.
+#[derive(Debug, Clone)]
+pub struct RegisterSet<A = NativeArch> {
+    pc: Option<u64>,
+    gprs: [Option<u64>; GPR_COUNT as usize],
+    fprs: [Option<u64>; FPR_COUNT as usize],
+    arch: PhantomData<A>,
+}
+
+impl<A> Default for RegisterSet<A> {
+    fn default() -> Self {
+        Self {
+            pc: None,
+            gprs: [None; GPR_COUNT as usize],
+            fprs: [None; FPR_COUNT as usize],
+            arch: PhantomData,
+        }
+    }
+}
+
+impl<A: Arch> RegisterSet<A> {
+    pub fn from_machine_state(machine: &MachineState) -> Self {
+        let mut regs = Self {
+            pc: Some(machine.pc),
+            ..Default::default()
+        };
+        if let Some(gprs) = machine.gprs {
+            regs.gprs = gprs.map(Some);
+        }
+        regs.gprs[A::SP.0 as usize] = Some(machine.sp);
+        regs.gprs[A::FP.0 as usize] = Some(machine.fp);
+        regs.gprs[A::RA.0 as usize] = Some(machine.ra);
+        regs
+    }
+
+    fn slot(&mut self, reg: Register) -> Option<&mut Option<u64>> {
+        if Some(reg) == A::ZERO {
+            return None;
+        }
+        match reg.0 {
+            n if n < gpr_end::<A>() => Some(&mut self.gprs[n as usize]),
+            n if (A::FPR_BASE..A::FPR_BASE + FPR_COUNT).contains(&n) => {
+                Some(&mut self.fprs[(n - A::FPR_BASE) as usize])
+            }
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, reg: Register) -> Option<u64> {
+        if Some(reg) == A::ZERO {
+            return Some(0);
+        }
+        match reg.0 {
+            n if n < gpr_end::<A>() => self.gprs[n as usize],
+            n if (A::FPR_BASE..A::FPR_BASE + FPR_COUNT).contains(&n) => {
+                self.fprs[(n - A::FPR_BASE) as usize]
+            }
+            _ => None,
+        }
+    }
+
+    pub fn set(&mut self, reg: Register, val: u64) -> Result<(), UnwinderError> {
+        *self
+            .slot(reg)
+            .ok_or(UnwinderError::UnexpectedRegister(reg))? = Some(val);
+        Ok(())
+    }
+
+    pub fn undef(&mut self, reg: Register) {
+        if let Some(slot) = self.slot(reg) {
+            *slot = None;
+        }
+    }
+
+    pub fn get_pc(&self) -> Option<u64> {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, val: u64) {
+        self.pc = Some(val);
+    }
+
+    /// The return address with any pointer authentication code removed.
+    pub fn get_ret(&self) -> Option<u64> {
+        self.get(A::RA).map(A::strip_return_address)
+    }
+
+    pub fn get_stack_ptr(&self) -> Option<u64> {
+        self.get(A::SP)
+    }
+
+    pub fn set_stack_ptr(&mut self, val: u64) {
+        self.gprs[A::SP.0 as usize] = Some(val);
+    }
+
+    /// Every register that can be restored, the zero register excluded.
+    pub fn iter() -> impl Iterator<Item = Register> {
+        (0..gpr_end::<A>())
+            .chain(A::FPR_BASE..A::FPR_BASE + FPR_COUNT)
+            .map(Register)
+            .filter(|reg| Some(*reg) != A::ZERO)
+    }
+
+    /// See [`Arch::is_callee_saved`].
+    pub fn is_callee_saved(reg: Register) -> bool {
+        A::is_callee_saved(reg)
+    }
+}
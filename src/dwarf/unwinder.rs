@@ -1,108 +1,288 @@
-use super::arch::{MachineState, RegisterSet};
-use crate::{DwarfProvider, TraceInfo, Tracer, TracerProvider};
+use super::expression;
+use super::registers::RegisterSet;
+use crate::arch::{Arch, NativeArch};
+use crate::machine::MachineState;
+use crate::memory::{MemoryReader, VolatileReader};
+use crate::stack::StackGuard;
+use crate::walk::{self, Walk};
+use crate::{DwarfProvider, Frame, TraceError, Tracer, TracerProvider, UnwindMethod};
 use alloc::boxed::Box;
-use core::arch::asm;
 use core::fmt::{Debug, Formatter};
-use core::mem::size_of;
+use core::marker::PhantomData;
+use core::ops::Range;
 use core::slice;
 use gimli::{
     BaseAddresses, CfaRule, EhFrame, EhFrameHdr, EhHdrTable, EndianSlice, LittleEndian,
     ParsedEhFrameHdr, Register, RegisterRule, UnwindContext, UnwindSection,
 };
 use log::trace;
-use crate::utils::read_value;
 
-pub struct DwarfTracer<T, M> {
+pub struct DwarfTracer<T, M, R = VolatileReader, A = NativeArch> {
     dwarf_provider: T,
-    machine_state: MachineState,
+    /// The register state to start from, `None` means the live registers.
+    machine_state: Option<MachineState>,
     tracer_provider: M,
+    reader: R,
+    /// The stack the walk may not leave, see [`Self::with_stack`].
+    stack: Option<Range<usize>>,
+    arch: PhantomData<A>,
 }
 
+#[cfg(live_capture)]
 impl<T: DwarfProvider, M: TracerProvider> DwarfTracer<T, M> {
     pub fn new(dwarf_provider: T, tracer_provider: M) -> Self {
-        let machine = MachineState {
-            pc: {
-                let pc: usize;
-                unsafe {
-                    asm!("auipc {},0", out(reg) pc);
-                }
-                pc as u64
-            },
-            sp: {
-                let sp: usize;
-                unsafe {
-                    asm!("mv {},sp", out(reg) sp);
-                }
-                sp as u64
-            },
-            fp: {
-                let fp: usize;
-                unsafe {
-                    asm!("mv {},s0", out(reg) fp);
-                }
-                fp as u64
-            },
-            ra: {
-                let ra: usize;
-                unsafe {
-                    asm!("mv {},ra", out(reg) ra);
-                }
-                ra as u64
-            },
-        };
+        Self::with_reader(dwarf_provider, tracer_provider, VolatileReader)
+    }
+}
+
+#[cfg(live_capture)]
+impl<T: DwarfProvider, M: TracerProvider, R: MemoryReader> DwarfTracer<T, M, R> {
+    pub fn with_reader(dwarf_provider: T, tracer_provider: M, reader: R) -> Self {
         Self {
-            machine_state: machine,
+            machine_state: None,
             dwarf_provider,
             tracer_provider,
+            reader,
+            stack: None,
+            arch: PhantomData,
         }
     }
 }
 
-struct DwarfTracerIterator<'a, M> {
-    unwinder: Unwinder,
-    provider: &'a M,
+impl<T: DwarfProvider, M: TracerProvider> DwarfTracer<T, M> {
+    /// Unwind from `state` instead of the live registers.
+    pub fn from_state(dwarf_provider: T, tracer_provider: M, state: MachineState) -> Self {
+        Self::from_state_with_reader(dwarf_provider, tracer_provider, state, VolatileReader)
+    }
+}
+
+impl<T: DwarfProvider, M: TracerProvider, R: MemoryReader, A: Arch> DwarfTracer<T, M, R, A> {
+    /// Unwind from `state`, which may belong to an architecture other than
+    /// [`NativeArch`].
+    pub fn from_state_with_reader(
+        dwarf_provider: T,
+        tracer_provider: M,
+        state: MachineState,
+        reader: R,
+    ) -> Self {
+        Self {
+            machine_state: Some(state),
+            dwarf_provider,
+            tracer_provider,
+            reader,
+            stack: None,
+            arch: PhantomData,
+        }
+    }
+
+    /// Never leave `stack`, see [`TracerProvider::stack_range`].
+    pub fn with_stack(mut self, stack: Range<usize>) -> Self {
+        self.stack = Some(stack);
+        self
+    }
+}
+
+/// One unwound frame together with the registers recovered for it.
+#[derive(Debug, Clone)]
+pub struct DwarfFrame<A = NativeArch> {
+    pub pc: u64,
+    pub regs: RegisterSet<A>,
 }
 
-impl<T: DwarfProvider, M: TracerProvider> Tracer for DwarfTracer<T, M> {
-    fn trace(&self) -> impl Iterator<Item = TraceInfo> + '_ {
+impl<T: DwarfProvider, M: TracerProvider, R: MemoryReader, A: Arch> DwarfTracer<T, M, R, A> {
+    fn iter(&self) -> DwarfTracerIterator<'_, M, R, A> {
+        let state = self.machine_state.as_ref();
         let unwinder = Unwinder::new(
-            EhInfo::new(&self.dwarf_provider),
-            RegisterSet::from_machine_state(&self.machine_state),
+            EhInfo::new::<A, _>(&self.dwarf_provider),
+            state.map_or_else(RegisterSet::default, RegisterSet::from_machine_state),
+            &self.reader,
+            // 实时回溯的sp在第一次调用next时才知道
+            StackGuard::within(
+                &self.tracer_provider,
+                state.map_or(0, |state| state.sp as usize),
+                self.stack.as_ref(),
+            ),
         );
         DwarfTracerIterator {
             unwinder,
+            live: state.is_none(),
+            index: 0,
+            done: false,
+            error: None,
+            stack: self.stack.as_ref(),
             provider: &self.tracer_provider,
         }
     }
+
+    /// Walk the stack like [`Tracer::frames`], but yield the register set of
+    /// every frame.
+    pub fn register_frames(&self) -> impl Iterator<Item = DwarfFrame<A>> + '_ {
+        let mut frames = self.iter();
+        core::iter::from_fn(move || {
+            frames.start();
+            let regs = frames.unwinder.regs.clone();
+            let frame = frames.next()?.ok()?;
+            Some(DwarfFrame {
+                pc: frame.pc as u64,
+                regs,
+            })
+        })
+    }
 }
 
-impl<M: TracerProvider> Iterator for DwarfTracerIterator<'_, M> {
-    type Item = TraceInfo;
+struct DwarfTracerIterator<'a, M, R, A> {
+    unwinder: Unwinder<'a, R, A>,
+    /// Whether the registers are still to be captured by the first `next`.
+    live: bool,
+    /// The index of the frame that will be yielded next.
+    index: usize,
+    done: bool,
+    /// The error that ended the walk, yielded after the last frame.
+    error: Option<TraceError>,
+    stack: Option<&'a Range<usize>>,
+    provider: &'a M,
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let pc = self.unwinder.next().ok()??;
+impl<T: DwarfProvider, M: TracerProvider, R: MemoryReader, A: Arch> Tracer
+    for DwarfTracer<T, M, R, A>
+{
+    type Provider = M;
 
-        if pc == 0 {
+    fn provider(&self) -> &M {
+        &self.tracer_provider
+    }
+
+    fn try_frames(&self) -> impl Iterator<Item = Result<Frame, TraceError>> + '_ {
+        self.iter()
+    }
+}
+
+impl<M: TracerProvider, R: MemoryReader, A: Arch> Iterator for DwarfTracerIterator<'_, M, R, A> {
+    type Item = Result<Frame, TraceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.start();
+        if let Some(err) = self.error.take() {
+            return Some(Err(err));
+        }
+        if self.done {
             return None;
         }
-        let info = self.provider.address2symbol(pc as usize)?;
-        Some(TraceInfo {
-            func_name: info.1,
-            func_addr: info.0,
-            bias: pc as usize - info.0,
-        })
+        let pc = self.unwinder.regs.get_pc()?;
+        let sp = self.unwinder.regs.get_stack_ptr().unwrap_or(0);
+        let mut frame = Frame {
+            index: self.index,
+            method: UnwindMethod::Dwarf,
+            pc: pc as usize,
+            sp: sp as usize,
+            cfa: None,
+            ra: None,
+        };
+        self.index += 1;
+        if let Err(err) = walk::step(self, &mut frame) {
+            self.done = true;
+            self.error = Some(err);
+        }
+        Some(Ok(frame))
+    }
+}
+
+impl<M: TracerProvider, R: MemoryReader, A: Arch> Walk for DwarfTracerIterator<'_, M, R, A> {
+    type Provider = M;
+    type Reader = R;
+    type Arch = A;
+
+    fn provider(&self) -> &M {
+        self.provider
+    }
+
+    fn reader(&self) -> &R {
+        self.unwinder.reader
+    }
+
+    fn guard(&self) -> &StackGuard {
+        &self.unwinder.guard
+    }
+
+    fn stack(&self) -> Option<&Range<usize>> {
+        self.stack
+    }
+
+    fn depth(&self) -> usize {
+        self.index
+    }
+
+    fn unwind_frame(&mut self, frame: &mut Frame) -> Result<(), TraceError> {
+        if self.unwind(frame.pc as u64, frame.sp as u64)? {
+            frame.cfa = Some(self.unwinder.cfa as usize);
+            frame.ra = self.unwinder.regs.get_pc().map(|ra| ra as usize);
+        } else {
+            self.done = true;
+        }
+        Ok(())
+    }
+
+    fn switch_to(&mut self, state: &MachineState, guard: StackGuard) {
+        self.unwinder.regs = RegisterSet::from_machine_state(state);
+        self.unwinder.innermost = true;
+        self.unwinder.guard = guard;
+    }
+}
+
+impl<M: TracerProvider, R: MemoryReader, A: Arch> DwarfTracerIterator<'_, M, R, A> {
+    /// Capture the live registers in the frame that starts the walk, which
+    /// stays live while it runs.
+    #[inline(always)]
+    fn start(&mut self) {
+        if self.live {
+            self.live = false;
+            let state = MachineState::current();
+            let guard = StackGuard::within(self.provider, state.sp as usize, self.stack);
+            self.switch_to(&state, guard);
+        }
+    }
+
+    /// Move the unwinder to the caller, `false` if this is the outermost frame.
+    fn unwind(&mut self, pc: u64, sp: u64) -> Result<bool, TraceError> {
+        let ra = match self.unwinder.unwind() {
+            Ok(ra) => ra,
+            // 最外层的函数不保存ra
+            Err(TraceError::Unwinder(UnwinderError::NoReturnAddr)) => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        if ra == 0 {
+            return Ok(false);
+        }
+        let cfa = self.unwinder.cfa;
+        self.unwinder.guard.check_step(
+            self.index,
+            (pc as usize, sp as usize),
+            (ra as usize, cfa as usize),
+        )?;
+        Ok(true)
     }
 }
 
+/// `cfa + offset`, an error if a corrupted cfa makes it wrap around.
+fn cfa_offset(cfa: u64, offset: i64) -> Result<u64, TraceError> {
+    cfa.checked_add_signed(offset)
+        .ok_or(TraceError::InvalidStackPointer(cfa as usize))
+}
+
 #[derive(Debug)]
 pub enum UnwinderError {
     UnexpectedRegister(Register),
-    UnsupportedCfaRule,
     CfaRuleUnknownRegister(Register),
     UnimplementedRegisterRule,
     NoUnwindInfo,
     NoPcRegister,
     NoReturnAddr,
+    UnreadableMemory(u64),
+    /// A DWARF expression referenced a register with no known value.
+    UnknownRegister(Register),
+    /// A DWARF expression needs context the unwinder cannot provide.
+    UnsupportedExpression,
+    Expression(gimli::Error),
 }
 
 #[derive(Debug)]
@@ -115,7 +295,8 @@ struct EhInfo {
 }
 
 impl EhInfo {
-    fn new<T: DwarfProvider>(provider: &T) -> Self {
+    /// Pointers in the tables are `A::WORD_SIZE` wide.
+    fn new<A: Arch, T: DwarfProvider>(provider: &T) -> Self {
         let hdr = provider.kernel_eh_frame_hdr();
         let hdr_len = provider.kernel_eh_frame_hdr_end() - hdr;
         let eh_frame = provider.kernel_eh_frame();
@@ -131,14 +312,15 @@ impl EhInfo {
                 unsafe { slice::from_raw_parts(hdr as *const u8, hdr_len) },
                 LittleEndian,
             )
-            .parse(&base_addrs, size_of::<usize>() as u8)
+            .parse(&base_addrs, A::WORD_SIZE as u8)
             .unwrap(),
         ));
         base_addrs = base_addrs.set_eh_frame(eh_frame as u64);
-        let eh_frame = EhFrame::new(
+        let mut eh_frame = EhFrame::new(
             unsafe { slice::from_raw_parts(eh_frame as *const u8, eh_frame_len) },
             LittleEndian,
         );
+        eh_frame.set_address_size(A::WORD_SIZE as u8);
         Self {
             base_addrs,
             hdr,
@@ -148,15 +330,19 @@ impl EhInfo {
     }
 }
 
-struct Unwinder {
+struct Unwinder<'a, R, A> {
     eh_info: EhInfo,
     unwind_ctx: UnwindContext<EndianSlice<'static, LittleEndian>>,
-    regs: RegisterSet,
+    regs: RegisterSet<A>,
     cfa: u64,
-    is_first: bool,
+    /// Whether `regs` belong to the innermost frame, whose `ra` register is
+    /// still live.
+    innermost: bool,
+    reader: &'a R,
+    guard: StackGuard,
 }
 
-impl Debug for Unwinder {
+impl<R, A: Arch> Debug for Unwinder<'_, R, A> {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("Unwinder")
             .field("regs", &self.regs)
@@ -165,23 +351,27 @@ impl Debug for Unwinder {
     }
 }
 
-impl Unwinder {
-    fn new(eh_info: EhInfo, register_set: RegisterSet) -> Self {
+impl<'a, R: MemoryReader, A: Arch> Unwinder<'a, R, A> {
+    fn new(
+        eh_info: EhInfo,
+        register_set: RegisterSet<A>,
+        reader: &'a R,
+        guard: StackGuard,
+    ) -> Self {
         Self {
             eh_info,
             unwind_ctx: UnwindContext::new(), // TODO: no alloc
             regs: register_set,
             cfa: 0,
-            is_first: true,
+            innermost: true,
+            reader,
+            guard,
         }
     }
 
-    fn next(&mut self) -> Result<Option<u64>, UnwinderError> {
+    /// Replace the registers with the caller's, returning its pc.
+    fn unwind(&mut self) -> Result<u64, TraceError> {
         let pc = self.regs.get_pc().ok_or(UnwinderError::NoPcRegister)?;
-        if self.is_first {
-            self.is_first = false;
-            return Ok(Some(pc));
-        }
         let row = self
             .eh_info
             .hdr_table
@@ -201,43 +391,812 @@ impl Unwinder {
                     .regs
                     .get(*register)
                     .ok_or(UnwinderError::CfaRuleUnknownRegister(*register))?;
-                self.cfa = (reg_val as i64 + offset) as u64;
+                self.cfa = reg_val
+                    .checked_add_signed(*offset)
+                    .ok_or(TraceError::InvalidStackPointer(reg_val as usize))?;
+            }
+            CfaRule::Expression(expr) => {
+                self.cfa = expression::evaluate(*expr, &self.regs, self.reader, &self.guard, None)?;
             }
-            _ => return Err(UnwinderError::UnsupportedCfaRule),
         }
         trace!("cfa:{:#x}, regs:{:#x?}", self.cfa, self.regs);
+        let sp = self.regs.get_stack_ptr().unwrap_or(0);
+        self.guard.check_cfa::<A>(sp as usize, self.cfa as usize)?;
 
-        // find the symbol associated with the current pc
-        for reg in RegisterSet::iter() {
+        // Every rule refers to the registers of the current frame, so the
+        // caller's registers are computed into a copy and swapped in at the end.
+        let mut caller = self.regs.clone();
+        for reg in RegisterSet::<A>::iter() {
             let rule = row.register(reg);
             trace!("reg: {:?}, rule: {:?}", reg, rule);
             match rule {
-                RegisterRule::Undefined => self.regs.undef(reg),
+                RegisterRule::Undefined if A::is_callee_saved(reg) => (),
+                // 叶子函数没有ra的规则，返回地址仍在ra寄存器中
+                RegisterRule::Undefined if reg == A::RA && self.innermost => (),
+                RegisterRule::Undefined => caller.undef(reg),
                 RegisterRule::SameValue => (),
                 RegisterRule::Offset(offset) => {
-                    let ptr = (self.cfa as i64 + offset) as usize;
-                    self.regs.set(reg, read_value(ptr) as u64)?;
-                }
-                RegisterRule::Register(_r) => {
-                    todo!()
+                    let ptr = cfa_offset(self.cfa, offset)?;
+                    self.guard.check_slot(ptr as usize)?;
+                    let value = A::read_word(self.reader, ptr as usize)
+                        .ok_or(UnwinderError::UnreadableMemory(ptr))?;
+                    caller.set(reg, value)?;
                 }
+                RegisterRule::Register(r) => match self.regs.get(r) {
+                    Some(value) => caller.set(reg, value)?,
+                    None => caller.undef(reg),
+                },
                 RegisterRule::ValOffset(offset) => {
-                    let value = self.cfa as i64 + offset;
-                    self.regs.set(reg, value as u64)?;
+                    caller.set(reg, cfa_offset(self.cfa, offset)?)?;
                 }
-                RegisterRule::Expression(_ex) => {
-                    todo!()
+                RegisterRule::Expression(expr) => {
+                    let ptr = expression::evaluate(
+                        expr,
+                        &self.regs,
+                        self.reader,
+                        &self.guard,
+                        Some(self.cfa),
+                    )?;
+                    self.guard.check_slot(ptr as usize)?;
+                    let value = A::read_word(self.reader, ptr as usize)
+                        .ok_or(UnwinderError::UnreadableMemory(ptr))?;
+                    caller.set(reg, value)?;
                 }
-                RegisterRule::ValExpression(_ex) => {
-                    todo!()
+                RegisterRule::ValExpression(expr) => {
+                    let value = expression::evaluate(
+                        expr,
+                        &self.regs,
+                        self.reader,
+                        &self.guard,
+                        Some(self.cfa),
+                    )?;
+                    caller.set(reg, value)?;
                 }
-                _ => return Err(UnwinderError::UnimplementedRegisterRule),
+                _ => return Err(UnwinderError::UnimplementedRegisterRule.into()),
             }
         }
+        self.regs = caller;
+        self.innermost = false;
         trace!("after cal, regs:{:#x?}", self.regs);
         let ret = self.regs.get_ret().ok_or(UnwinderError::NoReturnAddr)?;
         self.regs.set_pc(ret);
         self.regs.set_stack_ptr(self.cfa);
-        Ok(Some(ret))
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::{AArch64, LoongArch64, RiscV32, RiscV64};
+    use crate::testing::{
+        cfa, trap_layout, Bounded, EhFrameImage, Fde, Interrupted, MemoryImage, Symbols, WithTrap,
+    };
+    use crate::StackTransition;
+    use crate::{SwitchContext, TraceInfo};
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use gimli::RiscV;
+
+    const SP: u64 = 0x8000_0000;
+
+    /// leaf saves ra/s0 in a 32 byte frame, middle saves ra in a 48 byte
+    /// frame and root has no rule for ra.
+    fn eh_frame() -> EhFrameImage {
+        EhFrameImage::new(&[
+            Fde {
+                start: 0x1000,
+                len: 0x40,
+                instructions: vec![
+                    cfa::ADVANCE_LOC | 2,
+                    cfa::DEF_CFA_OFFSET,
+                    32,
+                    cfa::ADVANCE_LOC | 2,
+                    cfa::OFFSET | 1,
+                    1,
+                    cfa::OFFSET | 8,
+                    2,
+                ],
+            },
+            Fde {
+                start: 0x2000,
+                len: 0x40,
+                instructions: vec![
+                    cfa::ADVANCE_LOC | 2,
+                    cfa::DEF_CFA_OFFSET,
+                    48,
+                    cfa::ADVANCE_LOC | 2,
+                    cfa::OFFSET | 1,
+                    1,
+                ],
+            },
+            Fde {
+                start: 0x3000,
+                len: 0x40,
+                instructions: vec![cfa::UNDEFINED, 1],
+            },
+        ])
+    }
+
+    fn stack() -> MemoryImage {
+        let mut memory = MemoryImage::new();
+        memory.write_u64((SP + 32 - 8) as usize, 0x2020);
+        memory.write_u64((SP + 32 - 16) as usize, 0x8000_0100);
+        memory.write_u64((SP + 32 + 48 - 8) as usize, 0x3010);
+        memory
+    }
+
+    fn state(pc: u64) -> MachineState {
+        MachineState::new(pc, SP, 0, 0)
+    }
+
+    fn tracer(
+        memory: MemoryImage,
+        pc: u64,
+    ) -> DwarfTracer<EhFrameImage, Symbols, MemoryImage, RiscV64> {
+        DwarfTracer::from_state_with_reader(
+            eh_frame(),
+            Symbols(vec![
+                (0x1000, 0x40, "leaf"),
+                (0x2000, 0x40, "middle"),
+                (0x3000, 0x40, "root"),
+            ]),
+            state(pc),
+            memory,
+        )
+    }
+
+    #[test]
+    fn walks_eh_frame() {
+        let tracer = tracer(stack(), 0x1010);
+        let frames: Vec<_> = tracer
+            .trace()
+            .map(|info| (info.func_name, info.func_addr, info.bias))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                ("leaf", 0x1000, 0x10),
+                ("middle", 0x2000, 0x20),
+                ("root", 0x3000, 0x10)
+            ]
+        );
+        // root has no return address, which ends the walk without an error
+        assert!(tracer.try_trace().all(|item| item.is_ok()));
+
+        let frames: Vec<_> = tracer.frames().collect();
+        assert_eq!(frames[0].cfa, Some((SP + 32) as usize));
+        assert_eq!(frames[0].ra, Some(0x2020));
+        assert_eq!(frames[1].sp, (SP + 32) as usize);
+        assert_eq!(frames[2].ra, None);
+    }
+
+    #[test]
+    fn reports_wrapping_cfa() {
+        let tracer = |sp| {
+            DwarfTracer::<_, _, _, RiscV64>::from_state_with_reader(
+                eh_frame(),
+                Symbols(vec![(0x1000, 0x40, "leaf")]),
+                MachineState::new(0x1010, sp, 0, 0),
+                stack(),
+            )
+        };
+        // sp + 32 overflows i64 but is still an address
+        assert!(matches!(
+            tracer(0x7fff_ffff_ffff_fff8).try_trace().nth(1),
+            Some(Err(TraceError::Unwinder(UnwinderError::UnreadableMemory(
+                _
+            ))))
+        ));
+        assert!(matches!(
+            tracer(0xffff_ffff_ffff_fff0).try_trace().nth(1),
+            Some(Err(TraceError::InvalidStackPointer(0xffff_ffff_ffff_fff0)))
+        ));
+    }
+
+    #[test]
+    fn reports_why_the_walk_ended() {
+        let items: Vec<_> = tracer(stack(), 0x5000).try_trace().collect();
+        assert!(matches!(
+            items[..],
+            [
+                Ok(TraceInfo {
+                    func_name: "??",
+                    ..
+                }),
+                Err(TraceError::Unwinder(UnwinderError::NoUnwindInfo))
+            ]
+        ));
+
+        let items: Vec<_> = tracer(MemoryImage::new(), 0x1010).try_trace().collect();
+        assert!(matches!(
+            items[..],
+            [
+                Ok(_),
+                Err(TraceError::Unwinder(UnwinderError::UnreadableMemory(_)))
+            ]
+        ));
+    }
+
+    #[test]
+    fn enforces_stack_range_and_depth() {
+        let tracer = |end, max_frames| {
+            DwarfTracer::<_, _, _, RiscV64>::from_state_with_reader(
+                eh_frame(),
+                Bounded {
+                    inner: Symbols(vec![]),
+                    stack: SP as usize..end,
+                    max_frames,
+                },
+                state(0x1010),
+                stack(),
+            )
+        };
+
+        // middle的cfa超出了栈的范围
+        let items: Vec<_> = tracer(SP as usize + 64, 16).try_frames().collect();
+        assert!(matches!(
+            items[..],
+            [Ok(_), Ok(_), Err(TraceError::OutsideStack(0x8000_0050))]
+        ));
+        let items: Vec<_> = tracer(SP as usize + 0x1000, 1).try_frames().collect();
+        assert!(matches!(
+            items[..],
+            [Ok(_), Err(TraceError::TooManyFrames(1))]
+        ));
+    }
+
+    #[test]
+    fn continues_from_trampoline() {
+        let mut memory = stack();
+        memory.write_u64(0x9000_0000 + 32 - 8, 0x3010);
+        memory.write_u64(0x9000_0000 + 32 - 16, 0x9000_0100);
+        let tracer = DwarfTracer::<_, _, _, RiscV64>::from_state_with_reader(
+            eh_frame(),
+            Interrupted {
+                inner: Symbols(vec![]),
+                trampoline: 0x2000..0x2040,
+                reason: StackTransition::Trampoline,
+                state: MachineState::new(0x1010, 0x9000_0000, 0, 0),
+            },
+            state(0x1010),
+            memory,
+        );
+        let frames: Vec<_> = tracer
+            .frames()
+            .map(|frame| (frame.pc, frame.sp, frame.ra))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                (0x1010, SP as usize, Some(0x2020)),
+                (0x2020, SP as usize + 32, Some(0x1010)),
+                (0x1010, 0x9000_0000, Some(0x3010)),
+                (0x3010, 0x9000_0020, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn trampoline_keeps_stack_range() {
+        let mut memory = stack();
+        memory.write_u64(0x9000_0000 + 32 - 8, 0x3010);
+        memory.write_u64(0x9000_0000 + 32 - 16, 0x9000_0100);
+        let tracer = DwarfTracer::<_, _, _, RiscV64>::from_state_with_reader(
+            eh_frame(),
+            Interrupted {
+                inner: Symbols(vec![]),
+                trampoline: 0x2000..0x2040,
+                reason: StackTransition::Trampoline,
+                state: MachineState::new(0x1010, 0x9000_0000, 0, 0),
+            },
+            state(0x1010),
+            memory,
+        )
+        .with_stack(SP as usize..0x9000_0010);
+        // 切换之后仍然使用with_stack给出的范围，而不是stack_range
+        let items: Vec<_> = tracer.try_frames().collect();
+        assert!(matches!(
+            items[..],
+            [
+                Ok(_),
+                Ok(_),
+                Ok(Frame {
+                    sp: 0x9000_0000,
+                    ..
+                }),
+                Err(TraceError::OutsideStack(0x9000_0020))
+            ]
+        ));
+    }
+
+    #[test]
+    fn unwinds_through_trap_frame() {
+        let mut memory = stack();
+        // leaf由__alltraps调用，陷入帧中保存着被打断的root
+        memory.write_u64((SP + 32 - 8) as usize, 0x740);
+        memory.write_u64((SP + 32) as usize, 0x3010);
+        memory.write_u64((SP + 32 + 8) as usize, 0);
+        memory.write_u64((SP + 32 + 16) as usize, SP + 0x200);
+        memory.write_u64((SP + 32 + 24) as usize, 0);
+        let tracer = DwarfTracer::<_, _, _, RiscV64>::from_state_with_reader(
+            eh_frame(),
+            WithTrap {
+                inner: Symbols(vec![(0x1000, 0x40, "leaf"), (0x3000, 0x40, "root")]),
+                layout: trap_layout(),
+            },
+            state(0x1010),
+            memory,
+        );
+        let frames: Vec<_> = tracer
+            .trace()
+            .map(|info| (info.func_name, info.method, info.cfa))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                ("leaf", UnwindMethod::Dwarf, Some((SP + 32) as usize)),
+                (
+                    "<exception>",
+                    UnwindMethod::TrapFrame,
+                    Some((SP + 0x200) as usize)
+                ),
+                ("root", UnwindMethod::Dwarf, None)
+            ]
+        );
+        assert!(tracer.try_trace().all(|item| item.is_ok()));
+    }
+
+    #[test]
+    fn walks_sleeping_task() {
+        let mut context = SwitchContext {
+            ra: 0x1010,
+            sp: SP as usize,
+            ..Default::default()
+        };
+        context.s[1] = 7;
+        let tracer = DwarfTracer::<_, _, _, RiscV64>::from_state_with_reader(
+            eh_frame(),
+            Symbols(vec![]),
+            context.machine_state(),
+            stack(),
+        )
+        .with_stack(SP as usize..SP as usize + 64);
+
+        let items: Vec<_> = tracer.try_frames().collect();
+        assert!(matches!(
+            items[..],
+            [Ok(_), Ok(_), Err(TraceError::OutsideStack(0x8000_0050))]
+        ));
+        // 被调用者保存的寄存器来自切换时保存的上下文
+        let frames: Vec<_> = tracer.register_frames().collect();
+        assert_eq!(frames[1].pc, 0x2020);
+        assert_eq!(frames[1].regs.get(RiscV::S1), Some(7));
+        assert_eq!(frames[1].regs.get(RiscV::S0), Some(0x8000_0100));
+    }
+
+    #[test]
+    fn unwinder_restores_registers() {
+        let eh_frame = eh_frame();
+        let memory = stack();
+        let mut unwinder = Unwinder::new(
+            EhInfo::new::<RiscV64, _>(&eh_frame),
+            RegisterSet::<RiscV64>::from_machine_state(&state(0x1010)),
+            &memory,
+            StackGuard::new(&Symbols(vec![]), SP as usize),
+        );
+        assert_eq!(unwinder.unwind().unwrap(), 0x2020);
+        assert_eq!(unwinder.cfa, SP + 32);
+        assert_eq!(unwinder.regs.get(RiscV::S0), Some(0x8000_0100));
+        assert_eq!(unwinder.unwind().unwrap(), 0x3010);
+        assert_eq!(unwinder.regs.get(RiscV::SP), Some(SP + 32 + 48));
+        assert!(matches!(
+            unwinder.unwind(),
+            Err(TraceError::Unwinder(UnwinderError::NoReturnAddr))
+        ));
+    }
+
+    #[test]
+    fn frames_expose_full_register_file() {
+        // cfa = s0, ra at cfa-8, s1 at cfa-24
+        let eh_frame = EhFrameImage::new(&[
+            Fde {
+                start: 0x4000,
+                len: 0x40,
+                instructions: vec![cfa::DEF_CFA, 8, 0, cfa::OFFSET | 1, 1, cfa::OFFSET | 9, 3],
+            },
+            Fde {
+                start: 0x3000,
+                len: 0x40,
+                instructions: vec![],
+            },
+        ]);
+        let frame_top = SP + 0x80;
+        let mut memory = MemoryImage::new();
+        memory.write_u64((frame_top - 8) as usize, 0x3010);
+        memory.write_u64((frame_top - 24) as usize, 0x1111);
+
+        let mut gprs = [0u64; 32];
+        gprs[2] = SP;
+        gprs[8] = frame_top;
+        gprs[9] = 0xdead;
+        gprs[10] = 5;
+        gprs[18] = 7;
+        let tracer = DwarfTracer::<_, _, _, RiscV64>::from_state_with_reader(
+            eh_frame,
+            Symbols(vec![]),
+            MachineState::from_gprs::<RiscV64>(0x4010, gprs),
+            memory,
+        );
+        let frames: Vec<_> = tracer.register_frames().collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].pc, 0x4010);
+        assert_eq!(frames[0].regs.get(RiscV::A0), Some(5));
+
+        let caller = &frames[1].regs;
+        assert_eq!(frames[1].pc, 0x3010);
+        assert_eq!(caller.get_stack_ptr(), Some(frame_top));
+        assert_eq!(caller.get(RiscV::S1), Some(0x1111));
+        // callee-saved without a rule keeps its value, caller-saved is lost
+        assert_eq!(caller.get(RiscV::S2), Some(7));
+        assert_eq!(caller.get(RiscV::A0), None);
+    }
+
+    #[test]
+    fn evaluates_expression_rules() {
+        let eh_frame = EhFrameImage::new(&[
+            Fde {
+                start: 0x1000,
+                len: 0x40,
+                instructions: vec![
+                    // cfa = sp + 32
+                    cfa::DEF_CFA_EXPRESSION,
+                    2,
+                    0x72,
+                    32,
+                    // ra = *(sp + 24)
+                    cfa::EXPRESSION,
+                    1,
+                    2,
+                    0x72,
+                    24,
+                    // s0 = cfa
+                    cfa::VAL_EXPRESSION,
+                    8,
+                    1,
+                    0x9c,
+                ],
+            },
+            Fde {
+                start: 0x2000,
+                len: 0x40,
+                instructions: vec![],
+            },
+        ]);
+        let tracer = DwarfTracer::<_, _, _, RiscV64>::from_state_with_reader(
+            eh_frame,
+            Symbols(vec![]),
+            state(0x1010),
+            stack(),
+        );
+        let frames: Vec<_> = tracer.register_frames().collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].pc, 0x2020);
+        assert_eq!(frames[1].regs.get_stack_ptr(), Some(SP + 32));
+        assert_eq!(frames[1].regs.get(RiscV::S0), Some(SP + 32));
+    }
+
+    #[test]
+    fn expression_rules_stay_inside_stack() {
+        let eh_frame = EhFrameImage::new(&[Fde {
+            start: 0x1000,
+            len: 0x40,
+            instructions: vec![
+                cfa::DEF_CFA_OFFSET,
+                32,
+                // ra = *(sp + 40)，在调用者的栈帧中
+                cfa::EXPRESSION,
+                1,
+                2,
+                0x72,
+                40,
+            ],
+        }]);
+        let mut memory = stack();
+        memory.write_u64((SP + 40) as usize, 0x2020);
+        let tracer = DwarfTracer::<_, _, _, RiscV64>::from_state_with_reader(
+            eh_frame,
+            Symbols(vec![]),
+            state(0x1010),
+            memory,
+        )
+        .with_stack(SP as usize..SP as usize + 32);
+        let items: Vec<_> = tracer.try_frames().collect();
+        assert!(matches!(
+            items[..],
+            [Ok(_), Err(TraceError::OutsideStack(0x8000_0028))]
+        ));
+    }
+
+    #[test]
+    fn restores_from_other_registers() {
+        // leaf keeps ra in t0 and swaps s1/s2
+        let eh_frame = EhFrameImage::new(&[
+            Fde {
+                start: 0x1000,
+                len: 0x40,
+                instructions: vec![
+                    cfa::REGISTER,
+                    1,
+                    5,
+                    cfa::REGISTER,
+                    9,
+                    18,
+                    cfa::REGISTER,
+                    18,
+                    9,
+                ],
+            },
+            Fde {
+                start: 0x2000,
+                len: 0x40,
+                instructions: vec![],
+            },
+        ]);
+        let mut gprs = [0u64; 32];
+        gprs[2] = SP;
+        gprs[5] = 0x2020;
+        gprs[9] = 9;
+        gprs[18] = 18;
+        let tracer = DwarfTracer::<_, _, _, RiscV64>::from_state_with_reader(
+            eh_frame,
+            Symbols(vec![]),
+            MachineState::from_gprs::<RiscV64>(0x1010, gprs),
+            MemoryImage::new(),
+        );
+        let frames: Vec<_> = tracer.register_frames().collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].pc, 0x2020);
+        assert_eq!(frames[1].regs.get(RiscV::S1), Some(18));
+        assert_eq!(frames[1].regs.get(RiscV::S2), Some(9));
+    }
+
+    #[test]
+    fn walks_loongarch_eh_frame() {
+        // cfa = $sp + 32, $ra at cfa-8, $fp at cfa-16
+        let eh_frame = EhFrameImage::new(&[
+            Fde {
+                start: 0x1000,
+                len: 0x40,
+                instructions: vec![cfa::DEF_CFA, 3, 32, cfa::OFFSET | 1, 1, cfa::OFFSET | 22, 2],
+            },
+            Fde {
+                start: 0x3000,
+                len: 0x40,
+                instructions: vec![cfa::UNDEFINED, 1],
+            },
+        ]);
+        let mut memory = MemoryImage::new();
+        memory.write_u64((SP + 32 - 8) as usize, 0x3010);
+        memory.write_u64((SP + 32 - 16) as usize, 0x8000_0100);
+
+        let mut gprs = [0u64; 32];
+        gprs[3] = SP;
+        gprs[4] = 5;
+        gprs[23] = 7;
+        let tracer = DwarfTracer::<_, _, _, LoongArch64>::from_state_with_reader(
+            eh_frame,
+            Symbols(vec![(0x1000, 0x40, "leaf"), (0x3000, 0x40, "root")]),
+            MachineState::from_gprs::<LoongArch64>(0x1010, gprs),
+            memory,
+        );
+        let names: Vec<_> = tracer.trace().map(|info| info.func_name).collect();
+        assert_eq!(names, ["leaf", "root"]);
+
+        let frames: Vec<_> = tracer.register_frames().collect();
+        let caller = &frames[1].regs;
+        assert_eq!(frames[1].pc, 0x3010);
+        assert_eq!(caller.get_stack_ptr(), Some(SP + 32));
+        assert_eq!(caller.get(LoongArch64::FP), Some(0x8000_0100));
+        // $s0 is callee-saved, $a0 is not
+        assert_eq!(caller.get(Register(23)), Some(7));
+        assert_eq!(caller.get(Register(4)), None);
+    }
+
+    #[test]
+    fn innermost_frame_uses_live_ra() {
+        // 0x1000 is before leaf allocates its frame and saves ra
+        let tracer = DwarfTracer::<_, _, _, RiscV64>::from_state_with_reader(
+            eh_frame(),
+            Symbols(vec![(0x2000, 0x40, "middle"), (0x3000, 0x40, "root")]),
+            MachineState::new(0x1000, SP + 32, 0, 0x2020),
+            stack(),
+        );
+        let frames: Vec<_> = tracer
+            .trace()
+            .map(|info| (info.func_name, info.sp, info.ra))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                ("??", SP as usize + 32, Some(0x2020)),
+                ("middle", SP as usize + 32, Some(0x3010)),
+                ("root", SP as usize + 80, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn walks_riscv32_eh_frame() {
+        // cfa = sp + 16, ra at cfa-4, s0 at cfa-8
+        let eh_frame = EhFrameImage::for_arch::<RiscV32>(&[
+            Fde {
+                start: 0x1000,
+                len: 0x40,
+                instructions: vec![
+                    cfa::DEF_CFA_OFFSET,
+                    16,
+                    cfa::OFFSET | 1,
+                    1,
+                    cfa::OFFSET | 8,
+                    2,
+                ],
+            },
+            Fde {
+                start: 0x3000,
+                len: 0x40,
+                instructions: vec![cfa::UNDEFINED, 1],
+            },
+        ]);
+        let mut memory = MemoryImage::new();
+        memory.write_u32((SP + 16 - 4) as usize, 0x3010);
+        memory.write_u32((SP + 16 - 8) as usize, 0x8000_0100);
+
+        let tracer = DwarfTracer::<_, _, _, RiscV32>::from_state_with_reader(
+            eh_frame,
+            Symbols(vec![(0x1000, 0x40, "leaf"), (0x3000, 0x40, "root")]),
+            MachineState::new(0x1010, SP, 0, 0),
+            memory,
+        );
+        let frames: Vec<_> = tracer
+            .trace()
+            .map(|info| (info.func_name, info.cfa))
+            .collect();
+        assert_eq!(frames, [("leaf", Some(SP as usize + 16)), ("root", None)]);
+
+        let frames: Vec<_> = tracer.register_frames().collect();
+        assert_eq!(frames[1].regs.get(RiscV::S0), Some(0x8000_0100));
+    }
+
+    #[test]
+    fn walks_aarch64_eh_frame() {
+        // cfa = sp + 32, x29 at cfa-32, x30 at cfa-24
+        let eh_frame = EhFrameImage::for_arch::<AArch64>(&[
+            Fde {
+                start: 0x1000,
+                len: 0x40,
+                instructions: vec![
+                    cfa::DEF_CFA_OFFSET,
+                    32,
+                    cfa::OFFSET | 29,
+                    4,
+                    cfa::OFFSET | 30,
+                    3,
+                ],
+            },
+            Fde {
+                start: 0x3000,
+                len: 0x40,
+                instructions: vec![cfa::UNDEFINED, 30],
+            },
+        ]);
+        let mut memory = MemoryImage::new();
+        memory.write_u64(SP as usize, 0x8000_0100);
+        // signed with a pointer authentication code
+        memory.write_u64((SP + 8) as usize, 0x0012_0000_0000_3010);
+
+        let mut gprs = [0u64; 32];
+        gprs[0] = 5;
+        gprs[19] = 7;
+        gprs[31] = SP;
+        let tracer = DwarfTracer::<_, _, _, AArch64>::from_state_with_reader(
+            eh_frame,
+            Symbols(vec![(0x1000, 0x40, "leaf"), (0x3000, 0x40, "root")]),
+            MachineState::from_gprs::<AArch64>(0x1010, gprs),
+            memory,
+        );
+        let names: Vec<_> = tracer.trace().map(|info| info.func_name).collect();
+        assert_eq!(names, ["leaf", "root"]);
+
+        let frames: Vec<_> = tracer.register_frames().collect();
+        assert_eq!(frames[0].regs.get(gimli::AArch64::X0), Some(5));
+        let caller = &frames[1].regs;
+        assert_eq!(frames[1].pc, 0x3010);
+        assert_eq!(caller.get_stack_ptr(), Some(SP + 32));
+        assert_eq!(caller.get(gimli::AArch64::X29), Some(0x8000_0100));
+        assert_eq!(caller.get(gimli::AArch64::X19), Some(7));
+        assert_eq!(caller.get(gimli::AArch64::X0), None);
+    }
+
+    #[test]
+    fn stops_without_unwind_info() {
+        let eh_frame = eh_frame();
+        let memory = stack();
+        let mut unwinder = Unwinder::new(
+            EhInfo::new::<RiscV64, _>(&eh_frame),
+            RegisterSet::<RiscV64>::from_machine_state(&state(0x5000)),
+            &memory,
+            StackGuard::new(&Symbols(vec![]), SP as usize),
+        );
+        assert!(matches!(
+            unwinder.unwind(),
+            Err(TraceError::Unwinder(UnwinderError::NoUnwindInfo))
+        ));
+        assert_eq!(unwinder.regs.get_pc(), Some(0x5000));
+    }
+
+    #[test]
+    fn stops_at_unreadable_stack() {
+        let tracer = tracer(MemoryImage::new(), 0x1010);
+        assert_eq!(tracer.trace().count(), 1);
+
+        let eh_frame = eh_frame();
+        let memory = MemoryImage::new();
+        let mut unwinder = Unwinder::new(
+            EhInfo::new::<RiscV64, _>(&eh_frame),
+            RegisterSet::<RiscV64>::from_machine_state(&state(0x1010)),
+            &memory,
+            StackGuard::new(&Symbols(vec![]), SP as usize),
+        );
+        assert!(matches!(
+            unwinder.unwind(),
+            Err(TraceError::Unwinder(UnwinderError::UnreadableMemory(_)))
+        ));
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    mod own_stack {
+        use super::*;
+        use crate::testing::OwnEhFrame;
+        use core::hint::black_box;
+
+        #[inline(never)]
+        fn capture() -> Vec<Frame> {
+            let symbols = Symbols(vec![
+                (capture as *const () as usize, 0x400, "capture"),
+                (middle as *const () as usize, 0x400, "middle"),
+                (outer as *const () as usize, 0x400, "outer"),
+            ]);
+            let tracer = DwarfTracer::new(OwnEhFrame::new(), symbols);
+            // 寄存器在迭代器中捕获，最内层的几帧属于迭代器
+            let names: Vec<_> = tracer
+                .trace()
+                .map(|info| info.func_name)
+                .skip_while(|name| *name != "capture")
+                .take(3)
+                .collect();
+            assert_eq!(names, ["capture", "middle", "outer"]);
+            tracer.frames().collect()
+        }
+
+        #[inline(never)]
+        fn middle() -> Vec<Frame> {
+            black_box(capture())
+        }
+
+        #[inline(never)]
+        fn outer() -> Vec<Frame> {
+            black_box(middle())
+        }
+
+        #[test]
+        fn unwinds_own_stack() {
+            let frames = outer();
+            // the walk continues into the test harness
+            assert!(frames.len() > 3);
+            assert!(frames
+                .windows(2)
+                .all(|pair| pair[0].cfa == Some(pair[1].sp) && pair[0].sp < pair[1].sp));
+        }
     }
 }
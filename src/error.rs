@@ -0,0 +1,56 @@
+use crate::UnwinderError;
+use core::fmt::{Display, Formatter};
+
+/// Why a walk ended before reaching the outermost frame.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum TraceError {
+    /// The DWARF unwinder could not compute the caller's frame.
+    Unwinder(UnwinderError),
+    /// The function at this address has no stack allocation or `ra` save the
+    /// instruction scanner recognizes.
+    PrologueNotRecognized(usize),
+    /// No symbol contains this return address.
+    SymbolNotFound(usize),
+    /// The stack or frame pointer is null or not slot aligned.
+    InvalidStackPointer(usize),
+    /// The walk came back to a frame it has already visited.
+    LoopDetected(usize),
+    /// A stack slot or instruction at this address could not be read.
+    UnreadableMemory(usize),
+    /// The caller's sp is below the sp of the frame it was computed for.
+    StackNotMonotonic(usize),
+    /// The walk left the stack it started on, see
+    /// [`TracerProvider::stack_range`](crate::TracerProvider::stack_range).
+    OutsideStack(usize),
+    /// The walk found this many frames without reaching the outermost one.
+    TooManyFrames(usize),
+}
+
+impl From<UnwinderError> for TraceError {
+    fn from(err: UnwinderError) -> Self {
+        TraceError::Unwinder(err)
+    }
+}
+
+impl Display for TraceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TraceError::Unwinder(err) => write!(f, "dwarf unwinding failed: {:x?}", err),
+            TraceError::PrologueNotRecognized(addr) => {
+                write!(f, "prologue of function {:#x} not recognized", addr)
+            }
+            TraceError::SymbolNotFound(addr) => write!(f, "no symbol for address {:#x}", addr),
+            TraceError::InvalidStackPointer(addr) => {
+                write!(f, "invalid stack pointer {:#x}", addr)
+            }
+            TraceError::LoopDetected(addr) => write!(f, "loop detected at frame {:#x}", addr),
+            TraceError::UnreadableMemory(addr) => write!(f, "cannot read memory at {:#x}", addr),
+            TraceError::StackNotMonotonic(addr) => {
+                write!(f, "caller's stack pointer {:#x} moves down", addr)
+            }
+            TraceError::OutsideStack(addr) => write!(f, "address {:#x} is outside the stack", addr),
+            TraceError::TooManyFrames(count) => write!(f, "stopped after {} frames", count),
+        }
+    }
+}
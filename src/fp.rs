@@ -1,49 +1,712 @@
-use crate::utils::read_value;
-use crate::{TraceInfo, Tracer, TracerProvider};
-use core::arch::asm;
+use crate::arch::{Arch, NativeArch};
+use crate::machine::MachineState;
+use crate::memory::{MemoryReader, VolatileReader};
+use crate::prologue::Prologue;
+use crate::walk::{Cursor, CursorWalk, Unwind, Walk};
+use crate::{Frame, TraceError, Tracer, TracerProvider, UnwindMethod};
+use core::marker::PhantomData;
+use core::ops::Range;
 
-pub struct FramePointTracer<T> {
+pub struct FramePointTracer<T, R = VolatileReader, A = NativeArch> {
     provider: T,
+    reader: R,
+    /// The register state to start from, `None` means the live registers.
+    state: Option<MachineState>,
+    /// The stack the walk may not leave, see [`Self::with_stack`].
+    stack: Option<Range<usize>>,
+    arch: PhantomData<A>,
 }
 
+#[cfg(live_capture)]
 impl<T: TracerProvider> FramePointTracer<T> {
     pub fn new(provider: T) -> Self {
-        Self { provider }
+        Self::with_reader(provider, VolatileReader)
     }
 }
 
-impl<T: TracerProvider> Tracer for FramePointTracer<T> {
-    fn trace(&self) -> impl Iterator<Item = TraceInfo> + '_ {
-        FramePointTracerIterator {
-            fp: 0,
-            provider: &self.provider,
+#[cfg(live_capture)]
+impl<T: TracerProvider, R: MemoryReader> FramePointTracer<T, R> {
+    pub fn with_reader(provider: T, reader: R) -> Self {
+        Self {
+            provider,
+            reader,
+            state: None,
+            stack: None,
+            arch: PhantomData,
         }
     }
 }
 
-struct FramePointTracerIterator<'a, T> {
-    fp: usize,
-    provider: &'a T,
+impl<T: TracerProvider> FramePointTracer<T> {
+    /// Unwind from `state` instead of the live registers.
+    pub fn from_state(provider: T, state: MachineState) -> Self {
+        Self::from_state_with_reader(provider, state, VolatileReader)
+    }
+}
+
+impl<T: TracerProvider, R: MemoryReader, A: Arch> FramePointTracer<T, R, A> {
+    /// Unwind from `state`, which may belong to an architecture other than
+    /// [`NativeArch`].
+    pub fn from_state_with_reader(provider: T, state: MachineState, reader: R) -> Self {
+        Self {
+            provider,
+            reader,
+            state: Some(state),
+            stack: None,
+            arch: PhantomData,
+        }
+    }
+
+    /// Never leave `stack`, see [`TracerProvider::stack_range`].
+    pub fn with_stack(mut self, stack: Range<usize>) -> Self {
+        self.stack = Some(stack);
+        self
+    }
+}
+
+impl<T: TracerProvider, R: MemoryReader, A: Arch> Tracer for FramePointTracer<T, R, A> {
+    type Provider = T;
+
+    fn provider(&self) -> &T {
+        &self.provider
+    }
+
+    fn try_frames(&self) -> impl Iterator<Item = Result<Frame, TraceError>> + '_ {
+        CursorWalk::<_, _, A, FramePointUnwind>::new(
+            &self.provider,
+            &self.reader,
+            self.state.as_ref(),
+            self.stack.as_ref(),
+        )
+    }
+}
+
+/// Follows the frame records linked by fp.
+struct FramePointUnwind;
+
+impl Unwind for FramePointUnwind {
+    const METHOD: UnwindMethod = UnwindMethod::FramePointer;
+
+    #[inline(always)]
+    fn capture<R: MemoryReader>(_reader: &R) -> Result<MachineState, TraceError> {
+        Ok(MachineState::current())
+    }
+
+    fn unwind<T: TracerProvider, R: MemoryReader, A: Arch>(
+        walk: &CursorWalk<'_, T, R, A, Self>,
+        cursor: Cursor,
+    ) -> Result<Option<(usize, usize, usize)>, TraceError> {
+        let leaf = match cursor.ra {
+            Some(ra) => walk.unwind_leaf(cursor, ra)?,
+            None => None,
+        };
+        let caller = match leaf {
+            Some(caller) => Some(caller),
+            None => {
+                let fp = cursor.fp;
+                walk.unwind_record(cursor.sp, fp)?.map(|(ra, caller_fp)| {
+                    (ra, fp.wrapping_add_signed(A::FRAME_CFA_OFFSET), caller_fp)
+                })
+            }
+        };
+        if let Some((ra, cfa, _)) = caller {
+            walk.guard()
+                .check_step(walk.depth(), (cursor.pc, cursor.sp), (ra, cfa))?;
+        }
+        Ok(caller)
+    }
+}
+
+impl<T: TracerProvider, R: MemoryReader, A: Arch> CursorWalk<'_, T, R, A, FramePointUnwind> {
+    /// A function that has not saved `ra` (a leaf, or pc is still before the
+    /// store) or has already freed its frame in the epilogue has no frame
+    /// record, its return address is the live `ra` and fp still belongs to
+    /// the caller unless the prologue changed it.
+    fn unwind_leaf(
+        &self,
+        cursor: Cursor,
+        ra: usize,
+    ) -> Result<Option<(usize, usize, usize)>, TraceError> {
+        if !A::DECODES_PROLOGUE {
+            return Ok(None);
+        }
+        if Prologue::frame_released::<A, _>(self.reader(), cursor.pc) {
+            return Ok(Some((ra, cursor.sp, cursor.fp)));
+        }
+        let Some((start, _)) = self.provider().address2symbol(cursor.pc) else {
+            return Ok(None);
+        };
+        let prologue = Prologue::scan::<A, _>(self.reader(), start, cursor.pc)?;
+        if matches!(prologue.ra_save, Some((stack_size, _)) if stack_size > 0) {
+            return Ok(None);
+        }
+        let cfa = prologue.cfa::<A>(cursor.sp, cursor.fp)?;
+        self.guard().check_cfa::<A>(cursor.sp, cfa)?;
+        let caller_fp = match prologue.fp_save {
+            Some(fp_save) => self.read_slot(Prologue::slot(cfa, fp_save)?)?,
+            None => cursor.fp,
+        };
+        Ok(Some((ra, cfa, caller_fp)))
+    }
+
+    /// Read the return address and the caller's fp from the frame record
+    /// at `fp` of the frame at `sp`.
+    fn unwind_record(&self, sp: usize, fp: usize) -> Result<Option<(usize, usize)>, TraceError> {
+        // 链表的末尾
+        if fp == 0 {
+            return Ok(None);
+        }
+        // 帧记录由ra和调用者的fp两个字组成，在riscv上位于fp之下，
+        // 更小的fp会让读取的地址回绕
+        if fp < 2 * A::WORD_SIZE || !fp.is_multiple_of(A::WORD_SIZE) {
+            return Err(TraceError::InvalidStackPointer(fp));
+        }
+        self.guard()
+            .check_cfa::<A>(sp, fp.wrapping_add_signed(A::FRAME_CFA_OFFSET))?;
+        let ra = self.read_slot(fp.wrapping_add_signed(A::FRAME_RA_OFFSET))?;
+        let ra = A::strip_return_address(ra as u64) as usize;
+        if ra == 0 {
+            return Ok(None);
+        }
+        let caller_fp = self.read_slot(fp.wrapping_add_signed(A::FRAME_FP_OFFSET))?;
+        if caller_fp == fp {
+            return Err(TraceError::LoopDetected(fp));
+        }
+        Ok(Some((ra, caller_fp)))
+    }
 }
 
-impl<T: TracerProvider> Iterator for FramePointTracerIterator<'_, T> {
-    type Item = TraceInfo;
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.fp == 0 {
-            let fp: usize;
-            unsafe {
-                asm!("mv {},s0",out(reg)fp);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::{AArch64, RiscV32, RiscV64, X86_64};
+    use crate::testing::{trap_layout, Bounded, Interrupted, MemoryImage, Symbols, WithTrap};
+    use crate::StackTransition;
+    use crate::SwitchContext;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    const SP: u64 = 0x8000_00e0;
+    const FP: u64 = 0x8000_0100;
+
+    fn tracer(memory: MemoryImage, fp: u64) -> FramePointTracer<Symbols, MemoryImage, RiscV64> {
+        FramePointTracer::from_state_with_reader(
+            Symbols(vec![
+                (0x500, 0x100, "current"),
+                (0x1000, 0x100, "foo"),
+                (0x2000, 0x100, "bar"),
+            ]),
+            MachineState::new(0x510, SP, fp, 0),
+            memory,
+        )
+    }
+
+    #[test]
+    fn walks_frame_record_chain() {
+        let mut memory = MemoryImage::new();
+        // frame of the current function
+        memory.write_u64(0x8000_0100 - 8, 0x1010);
+        memory.write_u64(0x8000_0100 - 16, 0x8000_0200);
+        // frame of foo, called from unknown code
+        memory.write_u64(0x8000_0200 - 8, 0x9000);
+        memory.write_u64(0x8000_0200 - 16, 0x8000_0300);
+        // frame of the unknown code
+        memory.write_u64(0x8000_0300 - 8, 0x2020);
+        memory.write_u64(0x8000_0300 - 16, 0x8000_0400);
+        // frame of bar is the outermost one
+        memory.write_u64(0x8000_0400 - 8, 0);
+
+        let tracer = tracer(memory, FP);
+        let frames: Vec<_> = tracer.frames().collect();
+        assert_eq!(
+            frames[..2],
+            [
+                Frame {
+                    index: 0,
+                    method: UnwindMethod::FramePointer,
+                    pc: 0x510,
+                    sp: SP as usize,
+                    cfa: Some(0x8000_0100),
+                    ra: Some(0x1010),
+                },
+                Frame {
+                    index: 1,
+                    method: UnwindMethod::FramePointer,
+                    pc: 0x1010,
+                    sp: 0x8000_0100,
+                    cfa: Some(0x8000_0200),
+                    ra: Some(0x9000),
+                },
+            ]
+        );
+        assert_eq!(
+            frames[3],
+            Frame {
+                index: 3,
+                method: UnwindMethod::FramePointer,
+                pc: 0x2020,
+                sp: 0x8000_0300,
+                cfa: None,
+                ra: None,
             }
-            self.fp = fp;
-        }
-        let ra = read_value(self.fp - 8);
-        let func_info = self.provider.address2symbol(ra)?;
-        let new_fp = read_value(self.fp - 16);
-        self.fp = new_fp;
-        Some(TraceInfo {
-            func_name: func_info.1,
-            func_addr: func_info.0,
-            bias: ra - func_info.0,
-        })
+        );
+
+        let trace: Vec<_> = tracer
+            .trace()
+            .map(|info| (info.func_name, info.func_addr, info.bias))
+            .collect();
+        assert_eq!(
+            trace,
+            [
+                ("current", 0x500, 0x10),
+                ("foo", 0x1000, 0x10),
+                ("??", 0x9000, 0),
+                ("bar", 0x2000, 0x20)
+            ]
+        );
+        assert!(tracer.try_trace().all(|item| item.is_ok()));
+
+        let info = tracer.trace().nth(1).unwrap();
+        assert_eq!(info.index, 1);
+        assert_eq!((info.pc, info.ra), (0x1010, Some(0x9000)));
+        assert_eq!((info.sp, info.cfa), (0x8000_0100, Some(0x8000_0200)));
+        assert_eq!(info.method, UnwindMethod::FramePointer);
+        assert_eq!(info.module, None);
+    }
+
+    #[test]
+    fn stops_at_unreadable_frame_pointer() {
+        let mut memory = MemoryImage::new();
+        memory.write_u64(0x8000_0100 - 8, 0x1010);
+        memory.write_u64(0x8000_0100 - 16, 0xdead_0000);
+
+        let tracer = tracer(memory, FP);
+        assert_eq!(tracer.trace().count(), 2);
+        assert!(matches!(
+            tracer.try_trace().last(),
+            Some(Err(TraceError::UnreadableMemory(0xdeac_fff8)))
+        ));
+    }
+
+    #[test]
+    fn stops_on_null_frame_pointer() {
+        let tracer = tracer(MemoryImage::new(), 0);
+        let frames: Vec<_> = tracer.try_frames().collect();
+        assert!(matches!(
+            frames[..],
+            [Ok(Frame {
+                pc: 0x510,
+                ra: None,
+                ..
+            })]
+        ));
+    }
+
+    #[test]
+    fn detects_self_referencing_frame() {
+        let mut memory = MemoryImage::new();
+        memory.write_u64(0x8000_0100 - 8, 0x1010);
+        memory.write_u64(0x8000_0100 - 16, 0x8000_0100);
+
+        let tracer = tracer(memory, FP);
+        assert!(matches!(
+            tracer.try_trace().nth(1),
+            Some(Err(TraceError::LoopDetected(0x8000_0100)))
+        ));
+    }
+
+    #[test]
+    fn rejects_unaligned_frame_pointer() {
+        let tracer = tracer(MemoryImage::new(), 0x8000_0103);
+        let items: Vec<_> = tracer.try_frames().collect();
+        assert!(matches!(
+            items[..],
+            [Ok(_), Err(TraceError::InvalidStackPointer(0x8000_0103))]
+        ));
+    }
+
+    #[test]
+    fn rejects_frame_pointer_below_record() {
+        let items: Vec<_> = tracer(MemoryImage::new(), 8).try_frames().collect();
+        assert!(matches!(
+            items[..],
+            [Ok(_), Err(TraceError::InvalidStackPointer(8))]
+        ));
+        // riscv32的帧记录只有8字节
+        let tracer = FramePointTracer::<_, _, RiscV32>::from_state_with_reader(
+            Symbols(vec![]),
+            MachineState::new(0x510, 0, 8, 0),
+            MemoryImage::new(),
+        );
+        let items: Vec<_> = tracer.try_frames().collect();
+        assert!(matches!(
+            items[..],
+            [Ok(_), Err(TraceError::UnreadableMemory(4))]
+        ));
+    }
+
+    #[test]
+    fn walks_aarch64_frame_records() {
+        let mut memory = MemoryImage::new();
+        // x29 points to {caller x29, x30}
+        memory.write_u64(0x8000_0100, 0x8000_0200);
+        memory.write_u64(0x8000_0108, 0xffaa_ffff_8000_1010);
+        memory.write_u64(0x8000_0200, 0);
+        memory.write_u64(0x8000_0208, 0x2020);
+
+        let tracer = FramePointTracer::<_, _, AArch64>::from_state_with_reader(
+            Symbols(vec![]),
+            MachineState::new(0x510, SP, FP, 0),
+            memory,
+        );
+        let frames: Vec<_> = tracer
+            .frames()
+            .map(|frame| (frame.pc, frame.sp, frame.cfa))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                (0x510, SP as usize, Some(0x8000_0110)),
+                (0xffff_ffff_8000_1010, 0x8000_0110, Some(0x8000_0210)),
+                (0x2020, 0x8000_0210, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn walks_rbp_chain() {
+        let mut memory = MemoryImage::new();
+        // rbp points to {caller rbp, return address}
+        memory.write_u64(0x8000_0100, 0x8000_0200);
+        memory.write_u64(0x8000_0108, 0x1010);
+        memory.write_u64(0x8000_0200, 0);
+        memory.write_u64(0x8000_0208, 0x2020);
+
+        let tracer = FramePointTracer::<_, _, X86_64>::from_state_with_reader(
+            Symbols(vec![(0x1000, 0x100, "foo"), (0x2000, 0x100, "bar")]),
+            MachineState::new(0x510, SP, FP, 0),
+            memory,
+        );
+        let frames: Vec<_> = tracer
+            .trace()
+            .map(|info| (info.func_name, info.cfa))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                ("??", Some(0x8000_0110)),
+                ("foo", Some(0x8000_0210)),
+                ("bar", None)
+            ]
+        );
+    }
+
+    #[test]
+    fn walks_riscv32_frame_records() {
+        let mut memory = MemoryImage::new();
+        // 4字节的ra和fp
+        memory.write_u32(0x8000_0100 - 4, 0x1010);
+        memory.write_u32(0x8000_0100 - 8, 0x8000_0200);
+        memory.write_u32(0x8000_0200 - 4, 0);
+        memory.write_u32(0x8000_0200 - 8, 0xdead_beef);
+
+        let tracer = FramePointTracer::<_, _, RiscV32>::from_state_with_reader(
+            Symbols(vec![(0x1000, 0x100, "foo")]),
+            MachineState::new(0x510, SP, FP, 0),
+            memory,
+        );
+        let frames: Vec<_> = tracer
+            .trace()
+            .map(|info| (info.func_name, info.sp, info.ra))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                ("??", SP as usize, Some(0x1010)),
+                ("foo", 0x8000_0100, None)
+            ]
+        );
+        assert!(tracer.try_trace().all(|item| item.is_ok()));
+    }
+
+    #[test]
+    fn innermost_leaf_uses_live_ra() {
+        let mut memory = MemoryImage::new();
+        // current: c.addi sp,-16; c.sdsp s0,8(sp); c.addi4spn s0,sp,16
+        for (i, ins) in [0x1141, 0xe422, 0x0800].into_iter().enumerate() {
+            memory.write_u16(0x500 + i * 2, ins);
+        }
+        for addr in (0x506..0x520).step_by(2) {
+            memory.write_u16(addr, 0x0001);
+        }
+        memory.write_u64(SP as usize + 8, FP);
+        // frame record of foo
+        memory.write_u64(0x8000_0100 - 8, 0x2020);
+        memory.write_u64(0x8000_0100 - 16, 0x8000_0400);
+        memory.write_u64(0x8000_0400 - 8, 0);
+
+        let tracer = FramePointTracer::<_, _, RiscV64>::from_state_with_reader(
+            Symbols(vec![(0x500, 0x100, "current"), (0x1000, 0x100, "foo")]),
+            MachineState::new(0x510, SP, SP + 16, 0x1010),
+            memory,
+        );
+        let frames: Vec<_> = tracer
+            .frames()
+            .map(|frame| (frame.pc, frame.sp, frame.cfa))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                (0x510, SP as usize, Some(SP as usize + 16)),
+                (0x1010, SP as usize + 16, Some(FP as usize)),
+                (0x2020, FP as usize, None)
+            ]
+        );
+    }
+
+    fn bounded(
+        memory: MemoryImage,
+        max_frames: usize,
+    ) -> FramePointTracer<Bounded<Symbols>, MemoryImage, RiscV64> {
+        FramePointTracer::from_state_with_reader(
+            Bounded {
+                inner: Symbols(vec![]),
+                stack: 0x8000_0000..0x8000_0400,
+                max_frames,
+            },
+            MachineState::new(0x510, SP, FP, 0),
+            memory,
+        )
+    }
+
+    #[test]
+    fn stays_inside_stack_range() {
+        let mut memory = MemoryImage::new();
+        memory.write_u64(0x8000_0100 - 8, 0x1010);
+        memory.write_u64(0x8000_0100 - 16, 0x8000_0800);
+        // 栈外的内存即使可读也不会被访问
+        memory.write_u64(0x8000_0800 - 8, 0x2020);
+        memory.write_u64(0x8000_0800 - 16, 0);
+
+        let items: Vec<_> = bounded(memory, 16).try_frames().collect();
+        assert!(matches!(
+            items[..],
+            [Ok(_), Ok(_), Err(TraceError::OutsideStack(0x8000_0800))]
+        ));
+    }
+
+    #[test]
+    fn stops_when_stack_moves_down() {
+        let mut memory = MemoryImage::new();
+        memory.write_u64(0x8000_0100 - 8, 0x1010);
+        memory.write_u64(0x8000_0100 - 16, 0x8000_0200);
+        // foo的记录又指回了更低的地址
+        memory.write_u64(0x8000_0200 - 8, 0x2020);
+        memory.write_u64(0x8000_0200 - 16, 0x8000_0100);
+
+        let items: Vec<_> = bounded(memory, 16).try_frames().collect();
+        assert!(matches!(
+            items[..],
+            [
+                Ok(_),
+                Ok(_),
+                Ok(_),
+                Err(TraceError::StackNotMonotonic(0x8000_0100))
+            ]
+        ));
+    }
+
+    #[test]
+    fn limits_number_of_frames() {
+        let mut memory = MemoryImage::new();
+        for fp in (0x8000_0100..0x8000_0400).step_by(0x40) {
+            memory.write_u64(fp - 8, 0x1010);
+            memory.write_u64(fp - 16, fp as u64 + 0x40);
+        }
+
+        let items: Vec<_> = bounded(memory, 3).try_frames().collect();
+        assert_eq!(items.len(), 4);
+        assert!(matches!(items[3], Err(TraceError::TooManyFrames(3))));
+    }
+
+    #[test]
+    fn continues_on_interrupted_stack() {
+        let mut memory = MemoryImage::new();
+        // 中断栈上的帧，调用者的fp在任务栈上
+        memory.write_u64(0x8000_0100 - 8, 0x1010);
+        memory.write_u64(0x8000_0100 - 16, 0x9000_0100);
+        // 被打断的任务
+        memory.write_u64(0x9000_0100 - 8, 0x3010);
+        memory.write_u64(0x9000_0100 - 16, 0);
+        let tracer = |trampoline, reason| {
+            FramePointTracer::<_, _, RiscV64>::from_state_with_reader(
+                Interrupted {
+                    inner: Bounded {
+                        inner: Symbols(vec![]),
+                        stack: 0x8000_0000..0x8000_0400,
+                        max_frames: 16,
+                    },
+                    trampoline,
+                    reason,
+                    state: MachineState::new(0x2010, 0x9000_00e0, 0x9000_0100, 0x2222),
+                },
+                MachineState::new(0x510, SP, FP, 0),
+                memory.clone(),
+            )
+        };
+
+        let frames: Vec<_> = tracer(0..0, StackTransition::LeftStack)
+            .frames()
+            .map(|frame| (frame.pc, frame.sp, frame.ra))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                (0x510, SP as usize, Some(0x1010)),
+                (0x1010, 0x8000_0100, Some(0x2010)),
+                (0x2010, 0x9000_00e0, Some(0x3010)),
+                (0x3010, 0x9000_0100, None)
+            ]
+        );
+
+        // 蹦床函数不经过回溯，直接切换到被打断的上下文
+        let frames: Vec<_> = tracer(0x1000..0x1100, StackTransition::Trampoline)
+            .frames()
+            .map(|frame| (frame.pc, frame.cfa))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                (0x510, Some(0x8000_0100)),
+                (0x1010, Some(0x9000_00e0)),
+                (0x2010, Some(0x9000_0100)),
+                (0x3010, None)
+            ]
+        );
+
+        let items: Vec<_> = tracer(0..0, StackTransition::Trampoline)
+            .try_frames()
+            .collect();
+        assert!(matches!(
+            items[..],
+            [Ok(_), Ok(_), Err(TraceError::OutsideStack(0x9000_0100))]
+        ));
+    }
+
+    #[test]
+    fn unwinds_through_trap_frame() {
+        let mut memory = MemoryImage::new();
+        // 异常处理函数由__alltraps调用
+        memory.write_u64(0x8000_0100 - 8, 0x740);
+        memory.write_u64(0x8000_0100 - 16, 0x8000_0200);
+        // __alltraps保存的sepc、ra、sp和s0
+        memory.write_u64(0x8000_0100, 0x1010);
+        memory.write_u64(0x8000_0108, 0x2222);
+        memory.write_u64(0x8000_0110, 0x8000_0300);
+        memory.write_u64(0x8000_0118, 0x8000_0400);
+        // 被打断的foo
+        memory.write_u64(0x8000_0400 - 8, 0x2020);
+        memory.write_u64(0x8000_0400 - 16, 0);
+        // foo: addi sp,sp,-16; sd ra,8(sp); sd s0,0(sp); addi s0,sp,16
+        for (i, ins) in [0xff01_0113, 0x0011_3423, 0x0081_3023, 0x0101_0413]
+            .into_iter()
+            .enumerate()
+        {
+            memory.write_u32(0x1000 + i * 4, ins);
+        }
+        for addr in (0x1010..0x1020).step_by(4) {
+            memory.write_u32(addr, 0x0000_0013);
+        }
+
+        let tracer = FramePointTracer::<_, _, RiscV64>::from_state_with_reader(
+            WithTrap {
+                inner: Symbols(vec![
+                    (0x500, 0x100, "current"),
+                    (0x700, 0x80, "__alltraps"),
+                    (0x1000, 0x100, "foo"),
+                    (0x2000, 0x100, "bar"),
+                ]),
+                layout: trap_layout(),
+            },
+            MachineState::new(0x510, SP, FP, 0),
+            memory,
+        );
+        let trace: Vec<_> = tracer
+            .trace()
+            .map(|info| (info.func_name, info.method, info.sp, info.ra))
+            .collect();
+        assert_eq!(
+            trace,
+            [
+                (
+                    "current",
+                    UnwindMethod::FramePointer,
+                    SP as usize,
+                    Some(0x740)
+                ),
+                (
+                    "<exception>",
+                    UnwindMethod::TrapFrame,
+                    0x8000_0100,
+                    Some(0x1010)
+                ),
+                ("foo", UnwindMethod::FramePointer, 0x8000_0300, Some(0x2020)),
+                ("bar", UnwindMethod::FramePointer, 0x8000_0400, None)
+            ]
+        );
+        assert!(tracer.try_trace().all(|item| item.is_ok()));
+    }
+
+    #[test]
+    fn reports_unreadable_prologue() {
+        let mut memory = MemoryImage::new();
+        memory.write_u64(0x8000_0100 - 8, 0x1010);
+        memory.write_u64(0x8000_0100 - 16, 0);
+        // 现场中有ra，但current的指令无法读取，不能判断ra是否已经保存
+        let tracer = FramePointTracer::<_, _, RiscV64>::from_state_with_reader(
+            Symbols(vec![(0x500, 0x100, "current")]),
+            MachineState::new(0x510, SP, FP, 0x1010),
+            memory,
+        );
+        let items: Vec<_> = tracer.try_frames().collect();
+        assert!(matches!(
+            items[..],
+            [Ok(_), Err(TraceError::UnreadableMemory(_))]
+        ));
+    }
+
+    #[test]
+    fn walks_sleeping_task() {
+        let mut memory = MemoryImage::new();
+        memory.write_u64(0x8000_0100 - 8, 0x2020);
+        memory.write_u64(0x8000_0100 - 16, 0x8000_0200);
+        memory.write_u64(0x8000_0200 - 8, 0x3030);
+        memory.write_u64(0x8000_0200 - 16, 0x9000_0000);
+        let mut context = SwitchContext {
+            ra: 0x1010,
+            sp: SP as usize,
+            ..Default::default()
+        };
+        context.s[0] = FP as usize;
+
+        let tracer = FramePointTracer::<_, _, RiscV64>::from_state_with_reader(
+            Symbols(vec![]),
+            context.machine_state(),
+            memory,
+        )
+        .with_stack(0x8000_0000..0x8000_0400);
+        let items: Vec<_> = tracer
+            .try_frames()
+            .map(|item| item.map(|frame| frame.pc))
+            .collect();
+        assert!(matches!(
+            items[..],
+            [
+                Ok(0x1010),
+                Ok(0x2020),
+                Ok(0x3030),
+                Err(TraceError::OutsideStack(0x9000_0000))
+            ]
+        ));
     }
 }
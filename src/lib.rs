@@ -1,27 +1,448 @@
-#![feature(return_position_impl_trait_in_trait)]
 #![cfg_attr(not(test), no_std)]
+mod arch;
 mod compiler;
 mod dwarf;
+mod error;
 mod fp;
-mod utils;
+mod machine;
+mod memory;
+mod prologue;
+mod smp;
+mod stack;
+mod task;
+#[cfg(test)]
+mod testing;
+mod trap;
+mod walk;
 
 extern crate alloc;
 
+pub use arch::{
+    AArch64, Arch, LoongArch64, NativeArch, PrologueInstruction, RiscV32, RiscV64, X86_64,
+};
 pub use compiler::CompilerTracer;
 use core::iter::Iterator;
+use core::ops::Range;
 pub use dwarf::*;
+pub use error::TraceError;
 pub use fp::FramePointTracer;
+pub use machine::MachineState;
+pub use memory::{MemoryReader, VolatileReader};
+pub use smp::{HartMailbox, HartReport, SmpBacktrace};
+pub use task::SwitchContext;
+pub use trap::TrapFrameLayout;
 
+/// A symbolized frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
 pub struct TraceInfo {
     pub func_name: &'static str,
     pub func_addr: usize,
     pub bias: usize,
+    /// The position of the frame in the walk, 0 for the innermost one.
+    pub index: usize,
+    /// The pc of the innermost frame, the return address into every other one.
+    pub pc: usize,
+    /// The address this frame returns to, `None` for the last frame.
+    pub ra: Option<usize>,
+    pub sp: usize,
+    pub cfa: Option<usize>,
+    pub method: UnwindMethod,
+    /// The module containing `pc`, see [`TracerProvider::address2module`].
+    pub module: Option<&'static str>,
+    /// The source line of `pc`, see [`TracerProvider::address2location`].
+    pub location: Option<SourceLocation>,
+}
+
+/// A position in the source code.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: &'static str,
+    pub line: u32,
+}
+
+/// The tracer that produced a frame.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UnwindMethod {
+    FramePointer,
+    Dwarf,
+    Compiler,
+    /// The trap entry, whose caller was read from the saved registers, see
+    /// [`TracerProvider::trap_frame_layout`].
+    TrapFrame,
+}
+
+/// A frame found by a tracer, before it is symbolized.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// The position of the frame in the walk, 0 for the innermost one.
+    pub index: usize,
+    pub method: UnwindMethod,
+    /// The pc of the innermost frame, the return address into every other one.
+    pub pc: usize,
+    /// The stack pointer of this frame.
+    pub sp: usize,
+    /// The canonical frame address, i.e. the caller's stack pointer.
+    pub cfa: Option<usize>,
+    /// The address this frame returns to, `None` for the last frame.
+    pub ra: Option<usize>,
+}
+
+impl Frame {
+    /// Look up the function containing `pc`. Frames without a symbol, or
+    /// whose symbol starts above `pc`, are named `??` and keep their pc in
+    /// `func_addr`, trap frames are named `<exception>`.
+    pub fn symbolize<P: TracerProvider + ?Sized>(&self, provider: &P) -> TraceInfo {
+        // 符号表有误时不能在panic处理函数中再次panic
+        let (func_addr, func_name) = provider
+            .address2symbol(self.pc)
+            .filter(|&(addr, _)| addr <= self.pc)
+            .unwrap_or((self.pc, "??"));
+        let func_name = match self.method {
+            UnwindMethod::TrapFrame => "<exception>",
+            _ => func_name,
+        };
+        TraceInfo {
+            func_name,
+            func_addr,
+            bias: self.pc - func_addr,
+            index: self.index,
+            pc: self.pc,
+            ra: self.ra,
+            sp: self.sp,
+            cfa: self.cfa,
+            method: self.method,
+            module: provider.address2module(self.pc),
+            location: provider.address2location(self.pc),
+        }
+    }
+}
+
+/// Which frames [`Tracer::try_trace_filtered`] leaves out, e.g. the ones
+/// belonging to the tracer itself or to the panic machinery.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct FrameFilter<'a> {
+    /// Start at the caller of the function at this address, e.g.
+    /// `panic_handler as usize`. The stack is walked only once, so if no
+    /// frame belongs to the function, only the error ending the walk is left.
+    pub start_after: Option<usize>,
+    /// How many frames to drop after `start_after` has been applied.
+    pub skip: usize,
+    /// Drop every frame whose symbol starts with one of these, such as
+    /// `core::panicking`.
+    pub hidden_prefixes: &'a [&'a str],
+}
+
+impl FrameFilter<'_> {
+    fn hides(&self, func_name: &str) -> bool {
+        self.hidden_prefixes
+            .iter()
+            .any(|prefix| func_name.starts_with(prefix))
+    }
 }
 
 pub trait Tracer {
-    fn trace(&self) -> impl Iterator<Item = TraceInfo> + '_;
+    type Provider: TracerProvider;
+
+    /// The symbol provider used by [`Tracer::trace`].
+    fn provider(&self) -> &Self::Provider;
+
+    /// Walk the stack without symbolizing, the last item is the error that
+    /// ended the walk unless the outermost frame was reached.
+    fn try_frames(&self) -> impl Iterator<Item = Result<Frame, TraceError>> + '_;
+
+    /// Walk the stack without symbolizing, stopping silently on errors.
+    fn frames(&self) -> impl Iterator<Item = Frame> + '_ {
+        self.try_frames().map_while(Result::ok)
+    }
+
+    /// Walk the stack and symbolize every frame with [`Tracer::provider`].
+    fn try_trace(&self) -> impl Iterator<Item = Result<TraceInfo, TraceError>> + '_ {
+        let provider = self.provider();
+        self.try_frames()
+            .map(move |frame| frame.map(|frame| frame.symbolize(provider)))
+    }
+
+    /// Walk the stack and symbolize every frame, stopping silently on errors.
+    fn trace(&self) -> impl Iterator<Item = TraceInfo> + '_ {
+        self.try_trace().map_while(Result::ok)
+    }
+
+    /// Like [`Tracer::try_trace`], without the frames `filter` leaves out.
+    /// The frames keep their index in the full walk.
+    fn try_trace_filtered<'a>(
+        &'a self,
+        filter: FrameFilter<'a>,
+    ) -> impl Iterator<Item = Result<TraceInfo, TraceError>> + 'a {
+        // 只回溯一遍：实时回溯时tracer自身的帧在两次回溯之间会不同
+        let mut start_after = filter.start_after;
+        let mut skip = filter.skip;
+        self.try_trace().filter(move |item| {
+            let Ok(info) = item else {
+                return true;
+            };
+            if let Some(func) = start_after {
+                if info.func_addr == func {
+                    start_after = None;
+                }
+                return false;
+            }
+            if skip > 0 {
+                skip -= 1;
+                return false;
+            }
+            !filter.hides(info.func_name)
+        })
+    }
+
+    /// Like [`Tracer::trace`], without the frames `filter` leaves out.
+    fn trace_filtered<'a>(
+        &'a self,
+        filter: FrameFilter<'a>,
+    ) -> impl Iterator<Item = TraceInfo> + 'a {
+        self.try_trace_filtered(filter).map_while(Result::ok)
+    }
+}
+
+/// Why a walk asks [`TracerProvider::stack_transition`] for the context a
+/// frame interrupted.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StackTransition {
+    /// The frame's pc is in a trampoline, see
+    /// [`TracerProvider::is_trampoline`].
+    Trampoline,
+    /// The frame's caller is outside of the stack the walk is on, see
+    /// [`TracerProvider::stack_range`].
+    LeftStack,
 }
 
 pub trait TracerProvider {
     fn address2symbol(&self, addr: usize) -> Option<(usize, &'static str)>;
+
+    /// The name of the module (kernel image, loadable module, ...) containing
+    /// `addr`.
+    fn address2module(&self, _addr: usize) -> Option<&'static str> {
+        None
+    }
+
+    /// The source location of `addr`, if line information is available.
+    fn address2location(&self, _addr: usize) -> Option<SourceLocation> {
+        None
+    }
+
+    /// The stack containing `sp`, e.g. the kernel stack of the current hart
+    /// or its interrupt stack. A walk starting at `sp` ends with
+    /// [`TraceError::OutsideStack`] instead of reading outside of it.
+    ///
+    /// The `with_stack` method of a tracer replaces this with a fixed range,
+    /// e.g. the kernel stack of a sleeping task, which then also bounds every
+    /// stack the walk switches to through a trap frame or a trampoline.
+    fn stack_range(&self, _sp: usize) -> Option<Range<usize>> {
+        None
+    }
+
+    /// How many frames a walk may find before it ends with
+    /// [`TraceError::TooManyFrames`].
+    fn max_frames(&self) -> usize {
+        256
+    }
+
+    /// Where the trap entry saves the interrupted registers. A frame whose pc
+    /// lies in the entry is yielded as a trap frame and the walk continues
+    /// from the saved registers, whose `ra` is treated as live.
+    fn trap_frame_layout(&self) -> Option<&TrapFrameLayout> {
+        None
+    }
+
+    /// Whether `pc` is in code that switches stacks, e.g. the code that moves
+    /// a trap handler onto the interrupt stack.
+    fn is_trampoline(&self, _pc: usize) -> bool {
+        false
+    }
+
+    /// The saved registers of the context interrupted by `frame`. If some,
+    /// the walk continues from them on their own stack instead of unwinding
+    /// `frame` itself, and their `ra` is treated as live.
+    fn stack_transition(&self, _frame: &Frame, _reason: StackTransition) -> Option<MachineState> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    struct Provider;
+
+    /// Yields a fixed list of frames.
+    struct Frames(&'static [usize]);
+
+    impl Tracer for Frames {
+        type Provider = Provider;
+
+        fn provider(&self) -> &Provider {
+            &Provider
+        }
+
+        fn try_frames(&self) -> impl Iterator<Item = Result<Frame, TraceError>> + '_ {
+            self.0.iter().enumerate().map(|(index, &pc)| {
+                Ok(Frame {
+                    index,
+                    method: UnwindMethod::FramePointer,
+                    pc,
+                    sp: 0,
+                    cfa: None,
+                    ra: None,
+                })
+            })
+        }
+    }
+
+    impl TracerProvider for Provider {
+        fn address2symbol(&self, addr: usize) -> Option<(usize, &'static str)> {
+            match addr {
+                0x1000..0x1100 => Some((0x1000, "foo")),
+                0x2000..0x2100 => Some((0x2000, "core::panicking::panic")),
+                0x3000..0x3100 => Some((0x3000, "bar")),
+                0x4000..0x4100 => Some((0x4100, "broken")),
+                _ => None,
+            }
+        }
+        fn address2module(&self, _addr: usize) -> Option<&'static str> {
+            Some("kernel")
+        }
+        fn address2location(&self, addr: usize) -> Option<SourceLocation> {
+            (addr == 0x1010).then_some(SourceLocation {
+                file: "src/foo.rs",
+                line: 42,
+            })
+        }
+    }
+
+    #[test]
+    fn symbolize_fills_trace_info() {
+        let frame = Frame {
+            index: 2,
+            method: UnwindMethod::Dwarf,
+            pc: 0x1010,
+            sp: 0x8000_0000,
+            cfa: Some(0x8000_0020),
+            ra: Some(0x2000),
+        };
+        let info = frame.symbolize(&Provider);
+        assert_eq!(
+            (info.func_name, info.func_addr, info.bias),
+            ("foo", 0x1000, 0x10)
+        );
+        assert_eq!(info.index, 2);
+        assert_eq!(info.method, UnwindMethod::Dwarf);
+        assert_eq!(
+            (info.sp, info.cfa, info.ra),
+            (0x8000_0000, Some(0x8000_0020), Some(0x2000))
+        );
+        assert_eq!(info.module, Some("kernel"));
+        assert_eq!(info.location.map(|loc| loc.line), Some(42));
+
+        let info = Frame {
+            pc: 0x9000,
+            ..frame
+        }
+        .symbolize(&Provider);
+        assert_eq!(
+            (info.func_name, info.func_addr, info.bias),
+            ("??", 0x9000, 0)
+        );
+        assert_eq!(info.location, None);
+
+        // 起始地址在pc之后的符号
+        let info = Frame {
+            pc: 0x4010,
+            ..frame
+        }
+        .symbolize(&Provider);
+        assert_eq!(
+            (info.func_name, info.func_addr, info.bias),
+            ("??", 0x4010, 0)
+        );
+    }
+
+    #[test]
+    fn filters_frames() {
+        let tracer = Frames(&[0x9000, 0x1010, 0x2010, 0x3010, 0x9010]);
+        let names = |filter| {
+            tracer
+                .trace_filtered(filter)
+                .map(|info| (info.index, info.func_name))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(FrameFilter {
+                skip: 2,
+                ..Default::default()
+            }),
+            [(2, "core::panicking::panic"), (3, "bar"), (4, "??")]
+        );
+        assert_eq!(
+            names(FrameFilter {
+                start_after: Some(0x1000),
+                hidden_prefixes: &["core::panicking"],
+                ..Default::default()
+            }),
+            [(3, "bar"), (4, "??")]
+        );
+        // 找不到start_after时所有的帧都被去掉
+        assert_eq!(
+            names(FrameFilter {
+                start_after: Some(0x5000),
+                ..Default::default()
+            }),
+            []
+        );
+    }
+
+    /// Like a live tracer, every walk starts with one more frame of its own
+    /// than the previous one.
+    struct Live(Cell<usize>);
+
+    impl Tracer for Live {
+        type Provider = Provider;
+
+        fn provider(&self) -> &Provider {
+            &Provider
+        }
+
+        fn try_frames(&self) -> impl Iterator<Item = Result<Frame, TraceError>> + '_ {
+            let own = self.0.get();
+            self.0.set(own + 1);
+            let pcs = core::iter::repeat_n(0x9000, own).chain([0x1010, 0x3010, 0x9010]);
+            pcs.enumerate().map(|(index, pc)| {
+                Ok(Frame {
+                    index,
+                    method: UnwindMethod::FramePointer,
+                    pc,
+                    sp: 0,
+                    cfa: None,
+                    ra: None,
+                })
+            })
+        }
+    }
+
+    #[test]
+    fn filters_in_a_single_walk() {
+        let tracer = Live(Cell::new(1));
+        let filter = FrameFilter {
+            start_after: Some(0x1000),
+            ..Default::default()
+        };
+        // frames()会先回溯一遍，之后的回溯多一个tracer自身的帧
+        assert_eq!(tracer.frames().count(), 4);
+        let names: Vec<_> = tracer
+            .trace_filtered(filter)
+            .map(|info| (info.index, info.func_name))
+            .collect();
+        assert_eq!(names, [(3, "bar"), (4, "??")]);
+    }
 }
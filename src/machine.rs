@@ -0,0 +1,179 @@
+use crate::arch::Arch;
+
+#[cfg(live_capture)]
+use core::arch::asm;
+
+/// The machine state of the frame to unwind from.
+///
+/// Tracers created with `from_state` unwind from this state instead of the
+/// live registers, e.g. from the trap frame saved on a page fault.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MachineState {
+    pub pc: u64,
+    pub sp: u64,
+    pub fp: u64,
+    pub ra: u64,
+    /// The integer registers indexed by their DWARF number, if the whole
+    /// file was saved.
+    pub gprs: Option<[u64; 32]>,
+}
+
+impl MachineState {
+    pub const fn new(pc: u64, sp: u64, fp: u64, ra: u64) -> Self {
+        Self {
+            pc,
+            sp,
+            fp,
+            ra,
+            gprs: None,
+        }
+    }
+
+    /// Build the state from a saved register file indexed by the DWARF
+    /// numbers of `A`.
+    pub fn from_gprs<A: Arch>(pc: u64, gprs: [u64; 32]) -> Self {
+        Self {
+            pc,
+            sp: gprs[A::SP.0 as usize],
+            fp: gprs[A::FP.0 as usize],
+            ra: gprs[A::RA.0 as usize],
+            gprs: Some(gprs),
+        }
+    }
+
+    /// Capture the registers of the calling function.
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    #[inline(always)]
+    pub(crate) fn current() -> Self {
+        MachineState {
+            pc: {
+                let pc: usize;
+                unsafe {
+                    asm!("auipc {},0", out(reg) pc);
+                }
+                pc as u64
+            },
+            sp: {
+                let sp: usize;
+                unsafe {
+                    asm!("mv {},sp", out(reg) sp);
+                }
+                sp as u64
+            },
+            fp: {
+                let fp: usize;
+                unsafe {
+                    asm!("mv {},s0", out(reg) fp);
+                }
+                fp as u64
+            },
+            ra: {
+                let ra: usize;
+                unsafe {
+                    asm!("mv {},ra", out(reg) ra);
+                }
+                ra as u64
+            },
+            gprs: None,
+        }
+    }
+
+    /// Capture the registers of the calling function.
+    #[cfg(target_arch = "loongarch64")]
+    #[inline(always)]
+    pub(crate) fn current() -> Self {
+        let (pc, sp, fp, ra): (usize, usize, usize, usize);
+        unsafe {
+            asm!(
+                "pcaddi {pc},0",
+                "move {sp},$sp",
+                "move {fp},$fp",
+                "move {ra},$ra",
+                pc = out(reg) pc,
+                sp = out(reg) sp,
+                fp = out(reg) fp,
+                ra = out(reg) ra,
+            );
+        }
+        Self::new(pc as u64, sp as u64, fp as u64, ra as u64)
+    }
+
+    /// Capture the registers of the calling function.
+    #[cfg(target_arch = "aarch64")]
+    #[inline(always)]
+    pub(crate) fn current() -> Self {
+        let (pc, sp, fp, ra): (usize, usize, usize, usize);
+        unsafe {
+            asm!(
+                "adr {pc}, .",
+                "mov {sp}, sp",
+                "mov {fp}, x29",
+                "mov {ra}, x30",
+                pc = out(reg) pc,
+                sp = out(reg) sp,
+                fp = out(reg) fp,
+                ra = out(reg) ra,
+            );
+        }
+        Self::new(pc as u64, sp as u64, fp as u64, ra as u64)
+    }
+
+    /// Capture the registers of the calling function. x86_64 has no return
+    /// address register, `ra` is left zero.
+    #[cfg(target_arch = "x86_64")]
+    #[inline(always)]
+    pub(crate) fn current() -> Self {
+        let (pc, sp, fp): (usize, usize, usize);
+        unsafe {
+            asm!(
+                "lea {pc}, [rip]",
+                "mov {sp}, rsp",
+                "mov {fp}, rbp",
+                pc = out(reg) pc,
+                sp = out(reg) sp,
+                fp = out(reg) fp,
+            );
+        }
+        Self::new(pc as u64, sp as u64, fp as u64, 0)
+    }
+
+    /// Every constructor that relies on live capture is gated by the
+    /// `live_capture` cfg from `build.rs` as well, so this is never reached.
+    #[cfg(not(live_capture))]
+    #[inline(always)]
+    pub(crate) fn current() -> Self {
+        unreachable!(
+            "live register capture is only supported on riscv32, riscv64, loongarch64, aarch64 and x86_64"
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::{LoongArch64, RiscV64};
+
+    #[test]
+    fn state_from_saved_register_file() {
+        let mut gprs = [0u64; 32];
+        gprs[1] = 0x1000;
+        gprs[2] = 0x8000_0000;
+        gprs[8] = 0x8000_0100;
+        let state = MachineState::from_gprs::<RiscV64>(0x2000, gprs);
+        assert_eq!(state.pc, 0x2000);
+        assert_eq!(state.ra, 0x1000);
+        assert_eq!(state.sp, 0x8000_0000);
+        assert_eq!(state.fp, 0x8000_0100);
+        assert_eq!(state.gprs, Some(gprs));
+
+        // $ra = r1, $sp = r3, $fp = r22
+        let mut gprs = [0u64; 32];
+        gprs[1] = 0x1000;
+        gprs[3] = 0x8000_0000;
+        gprs[22] = 0x8000_0100;
+        let state = MachineState::from_gprs::<LoongArch64>(0x2000, gprs);
+        assert_eq!(state.ra, 0x1000);
+        assert_eq!(state.sp, 0x8000_0000);
+        assert_eq!(state.fp, 0x8000_0100);
+    }
+}
@@ -0,0 +1,64 @@
+/// Access to the memory of the context being unwound.
+///
+/// Every tracer reads instructions and stack slots through this trait, so a
+/// kernel can plug in a reader that checks the page table before touching an
+/// address, and a corrupted `fp` or `sp` ends the trace instead of faulting.
+pub trait MemoryReader {
+    fn read_u8(&self, addr: usize) -> Option<u8>;
+    fn read_u16(&self, addr: usize) -> Option<u16>;
+    fn read_u32(&self, addr: usize) -> Option<u32>;
+    fn read_u64(&self, addr: usize) -> Option<u64>;
+}
+
+impl<R: MemoryReader + ?Sized> MemoryReader for &R {
+    fn read_u8(&self, addr: usize) -> Option<u8> {
+        (**self).read_u8(addr)
+    }
+    fn read_u16(&self, addr: usize) -> Option<u16> {
+        (**self).read_u16(addr)
+    }
+    fn read_u32(&self, addr: usize) -> Option<u32> {
+        (**self).read_u32(addr)
+    }
+    fn read_u64(&self, addr: usize) -> Option<u64> {
+        (**self).read_u64(addr)
+    }
+}
+
+/// The default reader, which dereferences addresses directly.
+///
+/// It only rejects null pointers, so the caller must make sure every address
+/// reached during the walk is mapped.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct VolatileReader;
+
+impl VolatileReader {
+    fn read<T: Copy>(addr: usize) -> Option<T> {
+        if addr == 0 {
+            return None;
+        }
+        let ptr = addr as *const T;
+        // 2字节对齐的压缩指令后面可能紧跟4字节指令
+        let value = if addr.is_multiple_of(core::mem::align_of::<T>()) {
+            unsafe { ptr.read_volatile() }
+        } else {
+            unsafe { ptr.read_unaligned() }
+        };
+        Some(value)
+    }
+}
+
+impl MemoryReader for VolatileReader {
+    fn read_u8(&self, addr: usize) -> Option<u8> {
+        Self::read(addr)
+    }
+    fn read_u16(&self, addr: usize) -> Option<u16> {
+        Self::read(addr)
+    }
+    fn read_u32(&self, addr: usize) -> Option<u32> {
+        Self::read(addr)
+    }
+    fn read_u64(&self, addr: usize) -> Option<u64> {
+        Self::read(addr)
+    }
+}
@@ -0,0 +1,131 @@
+use crate::arch::{Arch, PrologueInstruction};
+use crate::memory::MemoryReader;
+use crate::TraceError;
+use log::trace;
+
+/// How many instructions from the start of a function are searched for the
+/// store of `ra`.
+const RA_SAVE_WINDOW: usize = 16;
+
+/// How many instructions from pc are searched for the end of an epilogue.
+const EPILOGUE_WINDOW: usize = 8;
+
+/// What the prologue executed before `pc` did to the frame. Saved slots are
+/// recorded as the stack size at the time of the store and the offset from
+/// the sp at that time. An epilogue ahead of `pc` is checked separately by
+/// [`Prologue::frame_released`].
+#[derive(Debug, Default)]
+pub(crate) struct Prologue {
+    pub stack_size: usize,
+    pub ra_save: Option<(usize, usize)>,
+    pub fp_save: Option<(usize, usize)>,
+    /// The stack size when `fp = sp + offset` was executed, and the offset.
+    pub fp_set: Option<(usize, usize)>,
+}
+
+impl Prologue {
+    /// Scan the instructions from `start` up to `pc`. `ra` is not necessarily
+    /// saved by the second instruction, the compiler may save `s0` first or
+    /// interleave other instructions, so the store is searched in a bounded
+    /// window and the scan goes on to `pc` only once it has been found.
+    pub fn scan<A: Arch, R: MemoryReader>(
+        reader: &R,
+        start: usize,
+        pc: usize,
+    ) -> Result<Self, TraceError> {
+        let mut prologue = Self::default();
+        let mut addr = start;
+        let mut count = 0;
+        while addr < pc && (prologue.ra_save.is_some() || count < RA_SAVE_WINDOW) {
+            let (ins, len) = A::decode_prologue(reader, addr)?;
+            trace!("addr: {:#x}, scan ins: {:?}", addr, ins);
+            prologue.apply(ins);
+            addr += len;
+            count += 1;
+        }
+        Ok(prologue)
+    }
+
+    fn apply(&mut self, ins: PrologueInstruction) {
+        let at = self.stack_size;
+        match ins {
+            PrologueInstruction::StackAlloc(size) => self.stack_size += size,
+            PrologueInstruction::SaveRa(offset) => {
+                self.ra_save.get_or_insert((at, offset));
+            }
+            PrologueInstruction::SaveFp(offset) => {
+                self.fp_save.get_or_insert((at, offset));
+            }
+            PrologueInstruction::SetFp(offset) => {
+                self.fp_set.get_or_insert((at, offset));
+            }
+            // 尾声可能出现在函数中间(提前返回)，顺序扫描到pc时不能计入
+            PrologueInstruction::StackFree(_)
+            | PrologueInstruction::SpFromFp
+            | PrologueInstruction::RestoreFp
+            | PrologueInstruction::Return
+            | PrologueInstruction::Other => {}
+        }
+    }
+
+    /// Whether the function returns from `pc` on without freeing a frame,
+    /// i.e. pc is past the epilogue's `sp += size` or the function has no
+    /// frame at all. `ra`, sp and fp then already hold the caller's values.
+    /// Code that cannot be read is assumed not to be an epilogue.
+    pub fn frame_released<A: Arch, R: MemoryReader>(reader: &R, pc: usize) -> bool {
+        let mut addr = pc;
+        for _ in 0..EPILOGUE_WINDOW {
+            match A::decode_prologue(reader, addr) {
+                Ok((PrologueInstruction::Return, _)) => return true,
+                Ok((PrologueInstruction::StackFree(_), _)) | Err(_) => return false,
+                Ok((_, len)) => addr += len,
+            }
+        }
+        false
+    }
+
+    /// Whether the epilogue ahead of `pc` has already reloaded the caller's
+    /// fp, i.e. it frees the frame without reloading fp or using it to reset
+    /// sp first. fp then no longer points into this frame, which has to be
+    /// found through sp.
+    pub fn fp_restored<A: Arch, R: MemoryReader>(reader: &R, pc: usize) -> bool {
+        let mut addr = pc;
+        for _ in 0..EPILOGUE_WINDOW {
+            match A::decode_prologue(reader, addr) {
+                Ok((PrologueInstruction::StackFree(_) | PrologueInstruction::Return, _)) => {
+                    return true
+                }
+                Ok((PrologueInstruction::SpFromFp | PrologueInstruction::RestoreFp, _))
+                | Err(_) => return false,
+                Ok((_, len)) => addr += len,
+            }
+        }
+        false
+    }
+
+    /// The caller's sp. Functions that set up fp may move sp dynamically
+    /// (`alloca` or over-aligned locals), so the frame is found through fp.
+    /// A corrupted sp or fp that would wrap around is an error.
+    pub fn cfa<A: Arch>(&self, sp: usize, fp: usize) -> Result<usize, TraceError> {
+        match self.fp_set {
+            Some((stack_size, offset)) => {
+                if fp == 0 || !fp.is_multiple_of(A::WORD_SIZE) {
+                    return Err(TraceError::InvalidStackPointer(fp));
+                }
+                fp.checked_add(stack_size)
+                    .and_then(|end| end.checked_sub(offset))
+                    .ok_or(TraceError::InvalidStackPointer(fp))
+            }
+            None => sp
+                .checked_add(self.stack_size)
+                .ok_or(TraceError::InvalidStackPointer(sp)),
+        }
+    }
+
+    /// The address of a slot recorded by the scan, given the caller's sp.
+    pub fn slot(cfa: usize, (stack_size, offset): (usize, usize)) -> Result<usize, TraceError> {
+        cfa.checked_sub(stack_size)
+            .and_then(|sp| sp.checked_add(offset))
+            .ok_or(TraceError::InvalidStackPointer(cfa))
+    }
+}
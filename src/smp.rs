@@ -0,0 +1,415 @@
+//! Collecting the backtraces of the other harts when one of them panics.
+//!
+//! Every hart owns a [`HartMailbox`], usually in a `static` array indexed by
+//! hart id. The panicking hart calls [`SmpBacktrace::collect`], which marks
+//! the mailboxes of the other harts and sends them an IPI through a callback
+//! from the kernel. The IPI handler of each hart unwinds the context it
+//! interrupted and stores the frames with [`HartMailbox::respond`]. Nothing
+//! is allocated on the way, the frames are copied into the mailbox.
+use crate::machine::MachineState;
+use crate::{Frame, TracerProvider, UnwindMethod};
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// 没有被请求
+const IDLE: u8 = 0;
+/// 已经发送了请求，等待目标hart响应
+const REQUESTED: u8 = 1;
+/// 目标hart正在写入栈帧
+const CAPTURING: u8 = 2;
+/// 栈帧已经写入，可以读取
+const DONE: u8 = 3;
+
+const EMPTY_FRAME: Frame = Frame {
+    index: 0,
+    method: UnwindMethod::FramePointer,
+    pc: 0,
+    sp: 0,
+    cfa: None,
+    ra: None,
+};
+
+/// What a hart stored in its mailbox.
+struct Capture<const N: usize> {
+    state: MachineState,
+    frames: [Frame; N],
+    len: usize,
+    truncated: bool,
+}
+
+/// The buffer one hart stores its backtrace in, holding up to `N` frames.
+pub struct HartMailbox<const N: usize> {
+    status: AtomicU8,
+    /// 本轮collect是否向该hart发送了请求，只由收集的hart读写
+    asked: AtomicBool,
+    /// 是否属于某个存在的SmpBacktrace
+    claimed: AtomicBool,
+    capture: UnsafeCell<Capture<N>>,
+}
+
+// capture只由状态为CAPTURING的hart写入，状态变为DONE之后才会被读取
+unsafe impl<const N: usize> Sync for HartMailbox<N> {}
+
+impl<const N: usize> Default for HartMailbox<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> HartMailbox<N> {
+    pub const fn new() -> Self {
+        Self {
+            status: AtomicU8::new(IDLE),
+            asked: AtomicBool::new(false),
+            claimed: AtomicBool::new(false),
+            capture: UnsafeCell::new(Capture {
+                state: MachineState::new(0, 0, 0, 0),
+                frames: [EMPTY_FRAME; N],
+                len: 0,
+                truncated: false,
+            }),
+        }
+    }
+
+    /// Whether a backtrace has been requested and not yet stored, i.e. the
+    /// IPI was sent to ask for it.
+    pub fn requested(&self) -> bool {
+        self.status.load(Ordering::Acquire) == REQUESTED
+    }
+
+    /// Store the backtrace of this hart, called from its IPI handler with the
+    /// interrupted register state and the frames unwound from it. Does
+    /// nothing unless a backtrace was requested. Frames beyond `N` are
+    /// dropped.
+    pub fn respond(&self, state: MachineState, frames: impl Iterator<Item = Frame>) {
+        if self
+            .status
+            .compare_exchange(REQUESTED, CAPTURING, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+        // SAFETY: 只有把状态从REQUESTED改为CAPTURING的hart可以写入
+        let capture = unsafe { &mut *self.capture.get() };
+        capture.state = state;
+        capture.len = 0;
+        capture.truncated = false;
+        for frame in frames {
+            if capture.len == N {
+                capture.truncated = true;
+                break;
+            }
+            capture.frames[capture.len] = frame;
+            capture.len += 1;
+        }
+        self.status.store(DONE, Ordering::Release);
+    }
+
+    /// Ask for a new backtrace, `false` if the hart is still storing the
+    /// previous one.
+    fn request(&self) -> bool {
+        self.status
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |status| {
+                (status != CAPTURING).then_some(REQUESTED)
+            })
+            .is_ok()
+    }
+
+    fn report(&self) -> Option<HartReport<'_>> {
+        if !self.asked.load(Ordering::Relaxed) {
+            return None;
+        }
+        match self.status.load(Ordering::Acquire) {
+            DONE => {
+                // SAFETY: 状态为DONE时不会再被写入，直到下一次请求，
+                // 而下一次collect需要&mut SmpBacktrace，报告借用的是&SmpBacktrace
+                let capture = unsafe { &*self.capture.get() };
+                Some(HartReport::Captured {
+                    state: &capture.state,
+                    frames: &capture.frames[..capture.len],
+                    truncated: capture.truncated,
+                })
+            }
+            _ => Some(HartReport::NoResponse),
+        }
+    }
+}
+
+/// The backtrace of one hart after [`SmpBacktrace::collect`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HartReport<'a> {
+    Captured {
+        /// The register state the hart unwound from.
+        state: &'a MachineState,
+        frames: &'a [Frame],
+        /// Whether the hart had more frames than its mailbox holds.
+        truncated: bool,
+    },
+    /// The hart did not answer before the timeout.
+    NoResponse,
+}
+
+/// Collects the backtraces of all harts into their mailboxes.
+pub struct SmpBacktrace<'a, const N: usize> {
+    mailboxes: &'a [HartMailbox<N>],
+}
+
+impl<'a, const N: usize> SmpBacktrace<'a, N> {
+    /// `mailboxes` is indexed by hart id. `None` if another `SmpBacktrace`
+    /// still collects into one of them, e.g. when two harts panic at once,
+    /// since its requests would overwrite the reports of this one.
+    pub fn new(mailboxes: &'a [HartMailbox<N>]) -> Option<Self> {
+        for (index, mailbox) in mailboxes.iter().enumerate() {
+            if mailbox
+                .claimed
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                // 释放已经占用的邮箱
+                for mailbox in &mailboxes[..index] {
+                    mailbox.claimed.store(false, Ordering::Release);
+                }
+                return None;
+            }
+        }
+        Some(Self { mailboxes })
+    }
+
+    /// Ask every hart except `current` for its backtrace through `send_ipi`,
+    /// then wait until all of them answered or `timeout` ticks of `now`
+    /// passed. Returns how many harts answered. Harts still storing a
+    /// backtrace from an earlier call are not asked and not reported.
+    pub fn collect(
+        &mut self,
+        current: usize,
+        mut send_ipi: impl FnMut(usize),
+        now: impl Fn() -> u64,
+        timeout: u64,
+    ) -> usize {
+        let mut asked = 0;
+        for (hart, mailbox) in self.mailboxes.iter().enumerate() {
+            // 先清除上一轮的标记，拒绝请求或当前hart的邮箱里可能还留着旧的栈
+            let requested = hart != current && mailbox.request();
+            mailbox.asked.store(requested, Ordering::Relaxed);
+            if requested {
+                send_ipi(hart);
+                asked += 1;
+            }
+        }
+        let start = now();
+        loop {
+            let answered = self.answered();
+            if answered == asked || now().wrapping_sub(start) >= timeout {
+                return answered;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn answered(&self) -> usize {
+        self.mailboxes
+            .iter()
+            .filter(|mailbox| {
+                mailbox.asked.load(Ordering::Relaxed)
+                    && mailbox.status.load(Ordering::Acquire) == DONE
+            })
+            .count()
+    }
+
+    /// The backtrace of every hart the last [`SmpBacktrace::collect`] asked
+    /// for one, by hart id.
+    pub fn reports(&self) -> impl Iterator<Item = (usize, HartReport<'_>)> + '_ {
+        self.mailboxes
+            .iter()
+            .enumerate()
+            .filter_map(|(hart, mailbox)| Some((hart, mailbox.report()?)))
+    }
+
+    /// Print the reports, symbolized with `provider`.
+    pub fn write<P: TracerProvider + ?Sized>(
+        &self,
+        provider: &P,
+        out: &mut impl Write,
+    ) -> fmt::Result {
+        for (hart, report) in self.reports() {
+            match report {
+                HartReport::Captured {
+                    frames, truncated, ..
+                } => {
+                    writeln!(out, "hart {}:", hart)?;
+                    for frame in frames {
+                        let info = frame.symbolize(provider);
+                        writeln!(
+                            out,
+                            "  [{}] {:#x} {}+{:#x}",
+                            info.index, info.pc, info.func_name, info.bias
+                        )?;
+                    }
+                    if truncated {
+                        writeln!(out, "  ...")?;
+                    }
+                }
+                HartReport::NoResponse => writeln!(out, "hart {}: no response", hart)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> Drop for SmpBacktrace<'_, N> {
+    fn drop(&mut self) {
+        for mailbox in self.mailboxes {
+            mailbox.claimed.store(false, Ordering::Release);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Symbols;
+    use alloc::string::String;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::cell::Cell;
+
+    fn frames(pcs: &[usize]) -> impl Iterator<Item = Frame> + '_ {
+        pcs.iter().enumerate().map(|(index, &pc)| Frame {
+            index,
+            pc,
+            ..EMPTY_FRAME
+        })
+    }
+
+    #[test]
+    fn collects_other_harts() {
+        let mailboxes: [HartMailbox<2>; 4] = [const { HartMailbox::new() }; 4];
+        let mut smp = SmpBacktrace::new(&mailboxes).unwrap();
+        let state = MachineState::new(0x1010, 0x8000_0000, 0, 0);
+        // 没有请求时不会写入
+        mailboxes[1].respond(state, frames(&[0x1010]));
+        assert_eq!(smp.reports().count(), 0);
+
+        let clock = Cell::new(0);
+        let answered = smp.collect(
+            0,
+            |hart| {
+                // hart 1和2在中断处理函数中响应，hart 3没有响应
+                if hart != 3 {
+                    assert!(mailboxes[hart].requested());
+                    mailboxes[hart].respond(state, frames(&[0x1010, 0x2020, 0x3030][..hart]));
+                }
+            },
+            || {
+                clock.set(clock.get() + 1);
+                clock.get()
+            },
+            100,
+        );
+        assert_eq!(answered, 2);
+        assert!(clock.get() > 100);
+
+        let reports: Vec<_> = smp.reports().collect();
+        assert_eq!(reports.len(), 3);
+        assert!(matches!(
+            reports[1],
+            (
+                2,
+                HartReport::Captured {
+                    frames: [Frame { pc: 0x1010, .. }, Frame { pc: 0x2020, .. }],
+                    truncated: false,
+                    ..
+                }
+            )
+        ));
+        assert_eq!(reports[2], (3, HartReport::NoResponse));
+
+        let mut out = String::new();
+        smp.write(&Symbols(vec![(0x1000, 0x100, "foo")]), &mut out)
+            .unwrap();
+        assert_eq!(
+            out,
+            "hart 1:\n  [0] 0x1010 foo+0x10\n\
+             hart 2:\n  [0] 0x1010 foo+0x10\n  [1] 0x2020 ??+0x0\n\
+             hart 3: no response\n"
+        );
+    }
+
+    #[test]
+    fn reports_only_the_harts_asked() {
+        let mailboxes: [HartMailbox<2>; 3] = [const { HartMailbox::new() }; 3];
+        let mut smp = SmpBacktrace::new(&mailboxes).unwrap();
+        let state = MachineState::new(0x1010, 0, 0, 0);
+        let respond = |hart: usize| mailboxes[hart].respond(state, frames(&[0x1010]));
+        assert_eq!(smp.collect(0, respond, || 0, 1), 2);
+
+        // hart 1 panic时它的邮箱里还有上一轮的栈，hart 2还在写入上一轮的栈
+        mailboxes[2].status.store(CAPTURING, Ordering::Release);
+        let clock = Cell::new(0);
+        let now = || {
+            clock.set(clock.get() + 1);
+            clock.get()
+        };
+        assert_eq!(smp.collect(1, respond, now, 100), 1);
+        assert!(clock.get() < 100);
+        // hart 2写完上一轮的栈也不会被报告
+        mailboxes[2].status.store(DONE, Ordering::Release);
+        let harts: Vec<_> = smp.reports().map(|(hart, _)| hart).collect();
+        assert_eq!(harts, [0]);
+    }
+
+    #[test]
+    fn refuses_a_second_collector() {
+        let mailboxes: [HartMailbox<2>; 3] = [const { HartMailbox::new() }; 3];
+        let smp = SmpBacktrace::new(&mailboxes).unwrap();
+        assert!(SmpBacktrace::new(&mailboxes).is_none());
+        // 部分重叠的邮箱也会被拒绝，并且不会占用其余的邮箱
+        assert!(SmpBacktrace::new(&mailboxes[..2]).is_none());
+        drop(smp);
+        let other = SmpBacktrace::new(&mailboxes[1..]).unwrap();
+        assert!(SmpBacktrace::new(&mailboxes[..1]).is_some());
+        assert!(SmpBacktrace::new(&mailboxes).is_none());
+        drop(other);
+        assert!(SmpBacktrace::new(&mailboxes).is_some());
+    }
+
+    #[test]
+    fn truncates_long_backtraces() {
+        let mailbox = HartMailbox::<2>::new();
+        assert!(mailbox.request());
+        mailbox.asked.store(true, Ordering::Relaxed);
+        mailbox.respond(
+            MachineState::new(0, 0, 0, 0),
+            frames(&[0x1010, 0x2020, 0x3030]),
+        );
+        assert!(matches!(
+            mailbox.report(),
+            Some(HartReport::Captured {
+                frames: [_, _],
+                truncated: true,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn answers_from_other_threads() {
+        static MAILBOXES: [HartMailbox<4>; 3] = [const { HartMailbox::new() }; 3];
+        let mut smp = SmpBacktrace::new(&MAILBOXES).unwrap();
+        std::thread::scope(|scope| {
+            for mailbox in &MAILBOXES[1..] {
+                scope.spawn(move || {
+                    while !mailbox.requested() {
+                        core::hint::spin_loop();
+                    }
+                    mailbox.respond(
+                        MachineState::new(0x1010, 0, 0, 0),
+                        frames(&[0x1010, 0x2020]),
+                    );
+                });
+            }
+            assert_eq!(smp.collect(0, |_| {}, || 0, 1), 2);
+        });
+    }
+}
@@ -0,0 +1,199 @@
+use crate::arch::Arch;
+use crate::machine::MachineState;
+use crate::memory::MemoryReader;
+use crate::{Frame, StackTransition, TraceError, TracerProvider};
+use core::ops::Range;
+
+/// The checks every step of a walk has to pass, so that a corrupted frame
+/// ends the walk with an error instead of reading arbitrary memory or
+/// looping forever.
+#[derive(Debug, Clone)]
+pub(crate) struct StackGuard {
+    /// The stack the walk started on, see [`TracerProvider::stack_range`].
+    range: Option<Range<usize>>,
+    max_frames: usize,
+}
+
+impl StackGuard {
+    pub fn new<T: TracerProvider + ?Sized>(provider: &T, sp: usize) -> Self {
+        Self {
+            range: provider.stack_range(sp),
+            max_frames: provider.max_frames(),
+        }
+    }
+
+    /// Like [`StackGuard::new`], with `stack` taking precedence over
+    /// [`TracerProvider::stack_range`].
+    pub fn within<T: TracerProvider + ?Sized>(
+        provider: &T,
+        sp: usize,
+        stack: Option<&Range<usize>>,
+    ) -> Self {
+        match stack {
+            Some(range) => Self {
+                range: Some(range.clone()),
+                max_frames: provider.max_frames(),
+            },
+            None => Self::new(provider, sp),
+        }
+    }
+
+    /// Check the caller's sp computed for the frame at `sp`, before anything
+    /// is read relative to it.
+    pub fn check_cfa<A: Arch>(&self, sp: usize, cfa: usize) -> Result<(), TraceError> {
+        if cfa == 0 || !cfa.is_multiple_of(A::WORD_SIZE) {
+            return Err(TraceError::InvalidStackPointer(cfa));
+        }
+        // 先检查范围，切换到另一个栈时无论高低都报告OutsideStack。
+        // 最外层的帧的cfa可以等于栈顶
+        if let Some(range) = &self.range {
+            if !(range.start..=range.end).contains(&cfa) {
+                return Err(TraceError::OutsideStack(cfa));
+            }
+        }
+        // 栈向低地址增长，调用者的栈帧只能在更高的地址
+        if cfa < sp {
+            return Err(TraceError::StackNotMonotonic(cfa));
+        }
+        Ok(())
+    }
+
+    /// Check a stack slot before it is read.
+    pub fn check_slot(&self, addr: usize) -> Result<(), TraceError> {
+        match &self.range {
+            Some(range) if !range.contains(&addr) => Err(TraceError::OutsideStack(addr)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the step from the frame at `pc`/`sp` to its caller, `depth` is
+    /// the number of frames found so far.
+    pub fn check_step(
+        &self,
+        depth: usize,
+        (pc, sp): (usize, usize),
+        (ra, cfa): (usize, usize),
+    ) -> Result<(), TraceError> {
+        if (ra, cfa) == (pc, sp) {
+            return Err(TraceError::LoopDetected(pc));
+        }
+        if depth >= self.max_frames {
+            return Err(TraceError::TooManyFrames(self.max_frames));
+        }
+        Ok(())
+    }
+
+    /// Ask `provider` for the context interrupted by `frame`, the step to it
+    /// is checked like any other. `None` if the walk stays where it is.
+    pub fn transition<T: TracerProvider + ?Sized>(
+        &self,
+        provider: &T,
+        depth: usize,
+        frame: &Frame,
+        reason: StackTransition,
+    ) -> Option<Result<MachineState, TraceError>> {
+        let state = provider.stack_transition(frame, reason)?;
+        Some(self.check_switch(depth, frame, state))
+    }
+
+    /// The registers saved by the trap entry, if `frame` belongs to it.
+    pub fn trap_frame<T: TracerProvider + ?Sized, A: Arch, R: MemoryReader>(
+        &self,
+        provider: &T,
+        reader: &R,
+        depth: usize,
+        frame: &Frame,
+    ) -> Option<Result<MachineState, TraceError>> {
+        let layout = provider.trap_frame_layout()?;
+        if !layout.entry.contains(&frame.pc) {
+            return None;
+        }
+        Some(
+            layout
+                .read::<A, _>(reader, self, frame.sp)
+                .and_then(|state| self.check_switch(depth, frame, state)),
+        )
+    }
+
+    fn check_switch(
+        &self,
+        depth: usize,
+        frame: &Frame,
+        state: MachineState,
+    ) -> Result<MachineState, TraceError> {
+        let step = (state.pc as usize, state.sp as usize);
+        self.check_step(depth, (frame.pc, frame.sp), step)?;
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::RiscV64;
+
+    struct Provider;
+
+    impl TracerProvider for Provider {
+        fn address2symbol(&self, _addr: usize) -> Option<(usize, &'static str)> {
+            None
+        }
+
+        fn stack_range(&self, sp: usize) -> Option<Range<usize>> {
+            (0x8000_0000..0x8000_1000)
+                .contains(&sp)
+                .then_some(0x8000_0000..0x8000_1000)
+        }
+
+        fn max_frames(&self) -> usize {
+            4
+        }
+    }
+
+    #[test]
+    fn checks_caller_sp() {
+        let guard = StackGuard::new(&Provider, 0x8000_0800);
+        assert!(guard.check_cfa::<RiscV64>(0x8000_0800, 0x8000_0800).is_ok());
+        assert!(guard.check_cfa::<RiscV64>(0x8000_0800, 0x8000_1000).is_ok());
+        assert!(matches!(
+            guard.check_cfa::<RiscV64>(0x8000_0800, 0x8000_0804),
+            Err(TraceError::InvalidStackPointer(0x8000_0804))
+        ));
+        assert!(matches!(
+            guard.check_cfa::<RiscV64>(0x8000_0800, 0x8000_07f0),
+            Err(TraceError::StackNotMonotonic(0x8000_07f0))
+        ));
+        assert!(matches!(
+            guard.check_cfa::<RiscV64>(0x8000_0800, 0x8000_1010),
+            Err(TraceError::OutsideStack(0x8000_1010))
+        ));
+        assert!(matches!(
+            guard.check_cfa::<RiscV64>(0x8000_0800, 0x7fff_f000),
+            Err(TraceError::OutsideStack(0x7fff_f000))
+        ));
+        assert!(matches!(
+            guard.check_slot(0x8000_1000),
+            Err(TraceError::OutsideStack(0x8000_1000))
+        ));
+        // 不知道所在的栈时只检查对齐和方向
+        let guard = StackGuard::new(&Provider, 0x9000_0000);
+        assert!(guard.check_cfa::<RiscV64>(0x9000_0000, 0x9000_1000).is_ok());
+        assert!(guard.check_slot(0).is_ok());
+    }
+
+    #[test]
+    fn checks_steps() {
+        let guard = StackGuard::new(&Provider, 0x8000_0800);
+        assert!(guard
+            .check_step(1, (0x1010, 0x8000_0800), (0x1010, 0x8000_0810))
+            .is_ok());
+        assert!(matches!(
+            guard.check_step(1, (0x1010, 0x8000_0800), (0x1010, 0x8000_0800)),
+            Err(TraceError::LoopDetected(0x1010))
+        ));
+        assert!(matches!(
+            guard.check_step(4, (0x1010, 0x8000_0800), (0x2020, 0x8000_0810)),
+            Err(TraceError::TooManyFrames(4))
+        ));
+    }
+}
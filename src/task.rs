@@ -0,0 +1,65 @@
+use crate::machine::MachineState;
+
+/// The registers a RISC-V context switch (`__switch`) saves for a task that
+/// is not running, in the order they are usually stored.
+///
+/// A sleeping task can be unwound with any tracer from
+/// [`SwitchContext::machine_state`], restricted to the task's kernel stack
+/// with `with_stack`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct SwitchContext {
+    pub ra: usize,
+    pub sp: usize,
+    /// `s0`-`s11`.
+    pub s: [usize; 12],
+}
+
+impl SwitchContext {
+    /// The task resumes at `ra`, inside the function that called the switch,
+    /// so that is its innermost frame. Its `ra` register was saved by the
+    /// function's prologue and is not live. `s0`-`s11` are kept for the
+    /// DWARF rules that refer to them.
+    pub fn machine_state(&self) -> MachineState {
+        let mut gprs = [0u64; 32];
+        gprs[2] = self.sp as u64;
+        // s0、s1是x8、x9，s2-s11是x18-x27
+        for (i, &value) in self.s.iter().enumerate() {
+            let reg = if i < 2 { 8 + i } else { 16 + i };
+            gprs[reg] = value as u64;
+        }
+        MachineState {
+            pc: self.ra as u64,
+            sp: self.sp as u64,
+            fp: self.s[0] as u64,
+            ra: 0,
+            gprs: Some(gprs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resumes_at_saved_ra() {
+        let mut context = SwitchContext {
+            ra: 0x1010,
+            sp: 0x8000_0000,
+            ..Default::default()
+        };
+        for (i, s) in context.s.iter_mut().enumerate() {
+            *s = 0x100 + i;
+        }
+        let state = context.machine_state();
+        assert_eq!(
+            (state.pc, state.sp, state.fp, state.ra),
+            (0x1010, 0x8000_0000, 0x100, 0)
+        );
+        let gprs = state.gprs.unwrap();
+        assert_eq!(gprs[1], 0);
+        assert_eq!((gprs[8], gprs[9]), (0x100, 0x101));
+        assert_eq!((gprs[18], gprs[27]), (0x102, 0x10b));
+    }
+}
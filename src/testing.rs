@@ -0,0 +1,358 @@
+//! Simulated memory, symbols and unwind tables for the host-side tests.
+use crate::arch::{Arch, RiscV64};
+use crate::{
+    DwarfProvider, Frame, MachineState, MemoryReader, StackTransition, TracerProvider,
+    TrapFrameLayout,
+};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// A sparse byte-addressed memory image, every byte not written is unmapped.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryImage {
+    bytes: BTreeMap<usize, u8>,
+}
+
+impl MemoryImage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write_bytes(&mut self, addr: usize, data: &[u8]) {
+        for (i, b) in data.iter().enumerate() {
+            self.bytes.insert(addr + i, *b);
+        }
+    }
+
+    pub fn write_u16(&mut self, addr: usize, value: u16) {
+        self.write_bytes(addr, &value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, addr: usize, value: u32) {
+        self.write_bytes(addr, &value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, addr: usize, value: u64) {
+        self.write_bytes(addr, &value.to_le_bytes());
+    }
+
+    fn read<const N: usize>(&self, addr: usize) -> Option<[u8; N]> {
+        let mut buf = [0u8; N];
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = *self.bytes.get(&addr.checked_add(i)?)?;
+        }
+        Some(buf)
+    }
+}
+
+impl MemoryReader for MemoryImage {
+    fn read_u8(&self, addr: usize) -> Option<u8> {
+        self.read(addr).map(u8::from_le_bytes)
+    }
+    fn read_u16(&self, addr: usize) -> Option<u16> {
+        self.read(addr).map(u16::from_le_bytes)
+    }
+    fn read_u32(&self, addr: usize) -> Option<u32> {
+        self.read(addr).map(u32::from_le_bytes)
+    }
+    fn read_u64(&self, addr: usize) -> Option<u64> {
+        self.read(addr).map(u64::from_le_bytes)
+    }
+}
+
+/// A symbol table of `(start, size, name)` entries.
+pub struct Symbols(pub Vec<(usize, usize, &'static str)>);
+
+impl TracerProvider for Symbols {
+    fn address2symbol(&self, addr: usize) -> Option<(usize, &'static str)> {
+        self.0
+            .iter()
+            .find(|(start, size, _)| (*start..*start + *size).contains(&addr))
+            .map(|(start, _, name)| (*start, *name))
+    }
+}
+
+/// A provider that knows the stack of the walk and limits its depth.
+pub struct Bounded<P> {
+    pub inner: P,
+    pub stack: Range<usize>,
+    pub max_frames: usize,
+}
+
+impl<P: TracerProvider> TracerProvider for Bounded<P> {
+    fn address2symbol(&self, addr: usize) -> Option<(usize, &'static str)> {
+        self.inner.address2symbol(addr)
+    }
+
+    fn stack_range(&self, sp: usize) -> Option<Range<usize>> {
+        self.stack.contains(&sp).then_some(self.stack.clone())
+    }
+
+    fn max_frames(&self) -> usize {
+        self.max_frames
+    }
+}
+
+/// A provider that switches to `state` when asked for `reason`, with
+/// `trampoline` as the code that switches stacks.
+pub struct Interrupted<P> {
+    pub inner: P,
+    pub trampoline: Range<usize>,
+    pub reason: StackTransition,
+    pub state: MachineState,
+}
+
+impl<P: TracerProvider> TracerProvider for Interrupted<P> {
+    fn address2symbol(&self, addr: usize) -> Option<(usize, &'static str)> {
+        self.inner.address2symbol(addr)
+    }
+
+    fn stack_range(&self, sp: usize) -> Option<Range<usize>> {
+        self.inner.stack_range(sp)
+    }
+
+    fn is_trampoline(&self, pc: usize) -> bool {
+        self.trampoline.contains(&pc)
+    }
+
+    fn stack_transition(&self, _frame: &Frame, reason: StackTransition) -> Option<MachineState> {
+        (reason == self.reason).then_some(self.state)
+    }
+}
+
+/// A provider whose trap entry saves the registers as described by `layout`.
+pub struct WithTrap<P> {
+    pub inner: P,
+    pub layout: TrapFrameLayout,
+}
+
+impl<P: TracerProvider> TracerProvider for WithTrap<P> {
+    fn address2symbol(&self, addr: usize) -> Option<(usize, &'static str)> {
+        self.inner.address2symbol(addr)
+    }
+
+    fn trap_frame_layout(&self) -> Option<&TrapFrameLayout> {
+        Some(&self.layout)
+    }
+}
+
+/// `__alltraps` at 0x700 saves sepc, ra, sp and s0 at the bottom of the
+/// trap frame.
+pub fn trap_layout() -> TrapFrameLayout {
+    TrapFrameLayout {
+        entry: 0x700..0x780,
+        pc: 0,
+        ra: 8,
+        sp: 16,
+        fp: 24,
+    }
+}
+
+/// A function described by an FDE in an [`EhFrameImage`].
+pub struct Fde {
+    pub start: u64,
+    pub len: u64,
+    /// The call frame instructions, appended to the CIE's `DW_CFA_def_cfa sp, 0`.
+    pub instructions: Vec<u8>,
+}
+
+/// Hand-assembled `.eh_frame` and `.eh_frame_hdr` sections.
+pub struct EhFrameImage {
+    eh_frame: Vec<u8>,
+    hdr: Vec<u8>,
+}
+
+const DW_EH_PE_UDATA4: u8 = 0x03;
+const DW_EH_PE_UDATA8: u8 = 0x04;
+
+impl EhFrameImage {
+    pub fn new(fdes: &[Fde]) -> Self {
+        Self::for_arch::<RiscV64>(fdes)
+    }
+
+    /// Use the return address and stack pointer of `A` in the CIE.
+    pub fn for_arch<A: Arch>(fdes: &[Fde]) -> Self {
+        let mut eh_frame = Vec::new();
+        // CIE: version 1, "zR", code align 1, data align -WORD_SIZE
+        let mut cie = Vec::new();
+        cie.extend_from_slice(&0u32.to_le_bytes());
+        cie.push(1);
+        cie.extend_from_slice(b"zR\0");
+        cie.extend_from_slice(&[1, (A::WORD_SIZE as u8).wrapping_neg() & 0x7f, A::RA.0 as u8]);
+        cie.extend_from_slice(&[1, DW_EH_PE_UDATA8]);
+        cie.extend_from_slice(&[cfa::DEF_CFA, A::SP.0 as u8, 0]);
+        push_entry(&mut eh_frame, cie);
+
+        let mut table = Vec::new();
+        for fde in fdes {
+            let offset = eh_frame.len();
+            let mut entry = Vec::new();
+            // CIE pointer: distance from this field back to the CIE
+            entry.extend_from_slice(&((offset + 4) as u32).to_le_bytes());
+            entry.extend_from_slice(&fde.start.to_le_bytes());
+            entry.extend_from_slice(&fde.len.to_le_bytes());
+            entry.push(0);
+            entry.extend_from_slice(&fde.instructions);
+            push_entry(&mut eh_frame, entry);
+            table.push((fde.start, offset));
+        }
+        eh_frame.extend_from_slice(&0u32.to_le_bytes());
+        table.sort();
+
+        // The buffer is never resized again, so its address is stable.
+        let base = eh_frame.as_ptr() as u64;
+        let mut hdr = Vec::new();
+        hdr.extend_from_slice(&[1, DW_EH_PE_UDATA8, DW_EH_PE_UDATA4, DW_EH_PE_UDATA8]);
+        hdr.extend_from_slice(&base.to_le_bytes());
+        hdr.extend_from_slice(&(table.len() as u32).to_le_bytes());
+        for (start, offset) in table {
+            hdr.extend_from_slice(&start.to_le_bytes());
+            hdr.extend_from_slice(&(base + offset as u64).to_le_bytes());
+        }
+        Self { eh_frame, hdr }
+    }
+}
+
+fn push_entry(section: &mut Vec<u8>, mut entry: Vec<u8>) {
+    while !(entry.len() + 4).is_multiple_of(8) {
+        entry.push(cfa::NOP);
+    }
+    section.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+    section.extend_from_slice(&entry);
+}
+
+impl DwarfProvider for EhFrameImage {
+    fn kernel_eh_frame_hdr(&self) -> usize {
+        self.hdr.as_ptr() as usize
+    }
+    fn kernel_eh_frame(&self) -> usize {
+        self.eh_frame.as_ptr() as usize
+    }
+    fn kernel_eh_frame_hdr_end(&self) -> usize {
+        self.kernel_eh_frame_hdr() + self.hdr.len()
+    }
+    fn kernel_eh_frame_end(&self) -> usize {
+        self.kernel_eh_frame() + self.eh_frame.len()
+    }
+}
+
+/// Call frame instruction opcodes, operands are single-byte ULEB128 values.
+pub mod cfa {
+    pub const NOP: u8 = 0x00;
+    pub const ADVANCE_LOC: u8 = 0x40;
+    pub const OFFSET: u8 = 0x80;
+    pub const UNDEFINED: u8 = 0x07;
+    pub const REGISTER: u8 = 0x09;
+    pub const DEF_CFA: u8 = 0x0c;
+    pub const DEF_CFA_OFFSET: u8 = 0x0e;
+    pub const DEF_CFA_EXPRESSION: u8 = 0x0f;
+    pub const EXPRESSION: u8 = 0x10;
+    pub const VAL_EXPRESSION: u8 = 0x16;
+}
+
+/// The `.eh_frame_hdr` and `.eh_frame` of the running test binary, found
+/// through its program headers.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub struct OwnEhFrame {
+    hdr: usize,
+    hdr_end: usize,
+    eh_frame: usize,
+    eh_frame_end: usize,
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod own {
+    use super::OwnEhFrame;
+    use core::ffi::{c_char, c_int, c_void};
+
+    const PT_LOAD: u32 = 1;
+    const PT_GNU_EH_FRAME: u32 = 0x6474_e550;
+    /// DW_EH_PE_pcrel | DW_EH_PE_sdata4
+    const PCREL_SDATA4: u8 = 0x1b;
+
+    #[repr(C)]
+    struct Phdr {
+        p_type: u32,
+        p_flags: u32,
+        p_offset: u64,
+        p_vaddr: u64,
+        p_paddr: u64,
+        p_filesz: u64,
+        p_memsz: u64,
+        p_align: u64,
+    }
+
+    #[repr(C)]
+    struct DlPhdrInfo {
+        addr: usize,
+        name: *const c_char,
+        phdr: *const Phdr,
+        phnum: u16,
+    }
+
+    extern "C" {
+        fn dl_iterate_phdr(
+            callback: extern "C" fn(*mut DlPhdrInfo, usize, *mut c_void) -> c_int,
+            data: *mut c_void,
+        ) -> c_int;
+    }
+
+    extern "C" fn find(info: *mut DlPhdrInfo, _size: usize, data: *mut c_void) -> c_int {
+        let info = unsafe { &*info };
+        let phdrs = unsafe { core::slice::from_raw_parts(info.phdr, info.phnum as usize) };
+        // the executable comes first, stop after it
+        let Some(eh) = phdrs.iter().find(|p| p.p_type == PT_GNU_EH_FRAME) else {
+            return 1;
+        };
+        let hdr = info.addr + eh.p_vaddr as usize;
+        let hdr_bytes = unsafe { core::slice::from_raw_parts(hdr as *const u8, 8) };
+        assert_eq!(hdr_bytes[1], PCREL_SDATA4);
+        let offset = i32::from_le_bytes(hdr_bytes[4..8].try_into().unwrap());
+        let eh_frame = (hdr + 4).wrapping_add_signed(offset as isize);
+        // .eh_frame has no program header of its own, the end of the segment
+        // containing it is a safe upper bound.
+        let eh_frame_end = phdrs
+            .iter()
+            .filter(|p| p.p_type == PT_LOAD)
+            .map(|p| {
+                let start = info.addr + p.p_vaddr as usize;
+                start..start + p.p_memsz as usize
+            })
+            .find(|segment| segment.contains(&eh_frame))
+            .map(|segment| segment.end)
+            .unwrap();
+        let out = unsafe { &mut *(data as *mut Option<OwnEhFrame>) };
+        *out = Some(OwnEhFrame {
+            hdr,
+            hdr_end: hdr + eh.p_memsz as usize,
+            eh_frame,
+            eh_frame_end,
+        });
+        1
+    }
+
+    impl OwnEhFrame {
+        pub fn new() -> Self {
+            let mut out: Option<OwnEhFrame> = None;
+            unsafe { dl_iterate_phdr(find, &mut out as *mut _ as *mut c_void) };
+            out.expect("the test binary has no .eh_frame_hdr")
+        }
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+impl DwarfProvider for OwnEhFrame {
+    fn kernel_eh_frame_hdr(&self) -> usize {
+        self.hdr
+    }
+    fn kernel_eh_frame(&self) -> usize {
+        self.eh_frame
+    }
+    fn kernel_eh_frame_hdr_end(&self) -> usize {
+        self.hdr_end
+    }
+    fn kernel_eh_frame_end(&self) -> usize {
+        self.eh_frame_end
+    }
+}
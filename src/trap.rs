@@ -0,0 +1,46 @@
+use crate::arch::Arch;
+use crate::machine::MachineState;
+use crate::memory::MemoryReader;
+use crate::stack::StackGuard;
+use crate::TraceError;
+use core::ops::Range;
+
+/// Where the kernel's trap entry saves the registers of the interrupted
+/// context. The entry has neither unwind information nor a prologue the
+/// scanner understands, so instead of unwinding it the tracers yield a
+/// [`UnwindMethod::TrapFrame`](crate::UnwindMethod::TrapFrame) frame and go
+/// on from the saved registers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrapFrameLayout {
+    /// The code of the trap entry, e.g. `__alltraps..__alltraps_end`.
+    pub entry: Range<usize>,
+    /// The offset of the saved pc (`sepc` on RISC-V) from the sp the entry
+    /// calls the trap handler with, like the offsets below.
+    pub pc: usize,
+    pub ra: usize,
+    pub sp: usize,
+    /// The offset of the saved frame pointer (`s0` on RISC-V).
+    pub fp: usize,
+}
+
+impl TrapFrameLayout {
+    /// Read the saved registers of the trap frame at `sp`.
+    pub(crate) fn read<A: Arch, R: MemoryReader>(
+        &self,
+        reader: &R,
+        guard: &StackGuard,
+        sp: usize,
+    ) -> Result<MachineState, TraceError> {
+        let read = |offset: usize| {
+            let addr = sp + offset;
+            guard.check_slot(addr)?;
+            A::read_word(reader, addr).ok_or(TraceError::UnreadableMemory(addr))
+        };
+        Ok(MachineState::new(
+            read(self.pc)?,
+            read(self.sp)?,
+            read(self.fp)?,
+            read(self.ra)?,
+        ))
+    }
+}
@@ -0,0 +1,266 @@
+use crate::arch::Arch;
+use crate::machine::MachineState;
+use crate::memory::MemoryReader;
+use crate::stack::StackGuard;
+use crate::{Frame, StackTransition, TraceError, TracerProvider, UnwindMethod};
+use core::marker::PhantomData;
+use core::ops::Range;
+
+/// The iterator of a tracer, reduced to what differs between the tracers.
+/// The trap entry, trampolines and leaving the stack are handled the same
+/// way by all of them in [`step`].
+pub(crate) trait Walk {
+    type Provider: TracerProvider + ?Sized;
+    type Reader: MemoryReader;
+    type Arch: Arch;
+
+    fn provider(&self) -> &Self::Provider;
+
+    fn reader(&self) -> &Self::Reader;
+
+    /// The guard of the stack the current frame is on.
+    fn guard(&self) -> &StackGuard;
+
+    /// The stack given to `with_stack`, which also bounds every stack the
+    /// walk switches to.
+    fn stack(&self) -> Option<&Range<usize>>;
+
+    /// The number of frames found so far.
+    fn depth(&self) -> usize;
+
+    /// Move to the caller of `frame` with the tracer's own method and record
+    /// its cfa and ra in `frame`. The walk ends if `frame` is the outermost.
+    fn unwind_frame(&mut self, frame: &mut Frame) -> Result<(), TraceError>;
+
+    /// Continue the walk from `state`, the context interrupted by the current
+    /// frame, on the stack checked by `guard`.
+    fn switch_to(&mut self, state: &MachineState, guard: StackGuard);
+}
+
+/// Move from `frame` to the next frame: the context saved by the trap entry
+/// or left by a trampoline if `frame` belongs to one, else its caller.
+pub(crate) fn step<W: Walk>(walk: &mut W, frame: &mut Frame) -> Result<(), TraceError> {
+    let guard = walk.guard();
+    let trap =
+        guard.trap_frame::<_, W::Arch, _>(walk.provider(), walk.reader(), walk.depth(), frame);
+    if let Some(state) = trap {
+        frame.method = UnwindMethod::TrapFrame;
+        return switch(walk, frame, state?);
+    }
+    if walk.provider().is_trampoline(frame.pc) {
+        let transition = guard.transition(
+            walk.provider(),
+            walk.depth(),
+            frame,
+            StackTransition::Trampoline,
+        );
+        if let Some(state) = transition {
+            return switch(walk, frame, state?);
+        }
+    }
+    match walk.unwind_frame(frame) {
+        // 离开了当前的栈，可能是从中断栈回到了被打断的任务的栈
+        Err(TraceError::OutsideStack(addr)) => {
+            let transition = walk.guard().transition(
+                walk.provider(),
+                walk.depth(),
+                frame,
+                StackTransition::LeftStack,
+            );
+            match transition {
+                Some(state) => switch(walk, frame, state?),
+                None => Err(TraceError::OutsideStack(addr)),
+            }
+        }
+        result => result,
+    }
+}
+
+fn switch<W: Walk>(walk: &mut W, frame: &mut Frame, state: MachineState) -> Result<(), TraceError> {
+    frame.cfa = Some(state.sp as usize);
+    frame.ra = Some(state.pc as usize);
+    let guard = StackGuard::within(walk.provider(), state.sp as usize, walk.stack());
+    walk.switch_to(&state, guard);
+    Ok(())
+}
+
+/// The frame that will be yielded next by a [`CursorWalk`].
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Cursor {
+    pub pc: usize,
+    pub sp: usize,
+    /// The frame pointer, only used by functions that set it up.
+    pub fp: usize,
+    /// The live return address register, only known for the innermost frame
+    /// of a captured register state.
+    pub ra: Option<usize>,
+}
+
+impl Cursor {
+    /// The innermost frame of a captured register state.
+    fn new(state: &MachineState) -> Self {
+        Self {
+            pc: state.pc as usize,
+            sp: state.sp as usize,
+            fp: state.fp as usize,
+            ra: (state.ra != 0).then_some(state.ra as usize),
+        }
+    }
+}
+
+/// How a [`CursorWalk`] finds the caller of a frame.
+pub(crate) trait Unwind: Sized {
+    const METHOD: UnwindMethod;
+
+    /// The registers a walk without a given state starts from, captured by
+    /// the first call of `next` while its frame is still live.
+    fn capture<R: MemoryReader>(reader: &R) -> Result<MachineState, TraceError>;
+
+    /// The return address, the caller's sp and the caller's fp of the frame
+    /// at `cursor`, `None` if it is the outermost frame.
+    fn unwind<T: TracerProvider, R: MemoryReader, A: Arch>(
+        walk: &CursorWalk<'_, T, R, A, Self>,
+        cursor: Cursor,
+    ) -> Result<Option<(usize, usize, usize)>, TraceError>;
+}
+
+/// The iterator of the tracers that only follow pc, sp and fp from one frame
+/// to the next, with `U` finding the caller.
+pub(crate) struct CursorWalk<'a, T, R, A, U> {
+    cursor: Option<Cursor>,
+    /// The index of the frame that will be yielded next.
+    index: usize,
+    /// The error that ended the walk, yielded after the last frame.
+    error: Option<TraceError>,
+    /// Whether the walk starts from the live registers at the first call of
+    /// `next`.
+    live: bool,
+    guard: StackGuard,
+    stack: Option<&'a Range<usize>>,
+    provider: &'a T,
+    reader: &'a R,
+    unwind: PhantomData<(A, U)>,
+}
+
+impl<'a, T: TracerProvider, R, A, U> CursorWalk<'a, T, R, A, U> {
+    /// Walk from `state`, or from the live registers if it is `None`.
+    pub(crate) fn new(
+        provider: &'a T,
+        reader: &'a R,
+        state: Option<&MachineState>,
+        stack: Option<&'a Range<usize>>,
+    ) -> Self {
+        let cursor = state.map(Cursor::new);
+        Self {
+            cursor,
+            index: 0,
+            error: None,
+            live: cursor.is_none(),
+            // 实时回溯的sp在第一次调用next时才知道
+            guard: StackGuard::within(provider, cursor.map_or(0, |cursor| cursor.sp), stack),
+            stack,
+            provider,
+            reader,
+            unwind: PhantomData,
+        }
+    }
+}
+
+impl<T: TracerProvider, R: MemoryReader, A: Arch, U> CursorWalk<'_, T, R, A, U> {
+    /// Read the word at `addr`, which has to be on the current stack.
+    pub(crate) fn read_slot(&self, addr: usize) -> Result<usize, TraceError> {
+        self.guard.check_slot(addr)?;
+        A::read_word(self.reader, addr)
+            .map(|value| value as usize)
+            .ok_or(TraceError::UnreadableMemory(addr))
+    }
+}
+
+impl<T: TracerProvider, R: MemoryReader, A: Arch, U: Unwind> Iterator
+    for CursorWalk<'_, T, R, A, U>
+{
+    type Item = Result<Frame, TraceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.live {
+            self.live = false;
+            match U::capture(self.reader) {
+                Ok(state) => {
+                    self.guard = StackGuard::within(self.provider, state.sp as usize, self.stack);
+                    // 捕获时ra寄存器可能已经被覆盖，不能作为返回地址
+                    self.cursor = Some(Cursor {
+                        ra: None,
+                        ..Cursor::new(&state)
+                    });
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        if let Some(err) = self.error.take() {
+            return Some(Err(err));
+        }
+        let cursor = self.cursor?;
+        let mut frame = Frame {
+            index: self.index,
+            method: U::METHOD,
+            pc: cursor.pc,
+            sp: cursor.sp,
+            cfa: None,
+            ra: None,
+        };
+        self.index += 1;
+        if let Err(err) = step(self, &mut frame) {
+            self.cursor = None;
+            self.error = Some(err);
+        }
+        Some(Ok(frame))
+    }
+}
+
+impl<T: TracerProvider, R: MemoryReader, A: Arch, U: Unwind> Walk for CursorWalk<'_, T, R, A, U> {
+    type Provider = T;
+    type Reader = R;
+    type Arch = A;
+
+    fn provider(&self) -> &T {
+        self.provider
+    }
+
+    fn reader(&self) -> &R {
+        self.reader
+    }
+
+    fn guard(&self) -> &StackGuard {
+        &self.guard
+    }
+
+    fn stack(&self) -> Option<&Range<usize>> {
+        self.stack
+    }
+
+    fn depth(&self) -> usize {
+        self.index
+    }
+
+    fn unwind_frame(&mut self, frame: &mut Frame) -> Result<(), TraceError> {
+        let Some(cursor) = self.cursor.take() else {
+            return Ok(());
+        };
+        if let Some((ra, cfa, fp)) = U::unwind(self, cursor)? {
+            frame.cfa = Some(cfa);
+            frame.ra = Some(ra);
+            self.cursor = Some(Cursor {
+                pc: ra,
+                sp: cfa,
+                fp,
+                ra: None,
+            });
+        }
+        Ok(())
+    }
+
+    fn switch_to(&mut self, state: &MachineState, guard: StackGuard) {
+        self.guard = guard;
+        self.cursor = Some(Cursor::new(state));
+    }
+}